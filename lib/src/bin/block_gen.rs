@@ -2,10 +2,10 @@ use std::{env, process::exit};
 
 use btc_lib::{
     crypto::PrivateKey,
+    params::ChainParams,
     sha256::Hash,
     types::{Block, BlockHeader, Transaction, TransactionOutput},
     util::{MerkleRoot, Saveable},
-    INITIAL_REWARD, MIN_TARGET,
 };
 use chrono::Utc;
 use uuid::Uuid;
@@ -18,20 +18,21 @@ fn main() {
         exit(1);
     };
 
+    let params = ChainParams::mainnet();
     let private_key = PrivateKey::new_key();
 
     let transactions = vec![Transaction::new(
         vec![],
         vec![TransactionOutput {
             unique_id: Uuid::new_v4(),
-            value: INITIAL_REWARD * 10u64.pow(8),
+            value: params.block_reward(0),
             pubkey: private_key.public_key(),
         }],
     )];
 
     let merkle_root = MerkleRoot::calculate(&transactions);
     let block = Block::new(
-        BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, MIN_TARGET),
+        BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, params.min_target),
         transactions,
     );
     block.save_to_file(path).expect("failed to save block");
@@ -2,9 +2,9 @@ use std::{env, process::exit};
 
 use btc_lib::{
     crypto::PrivateKey,
+    params::ChainParams,
     types::{Transaction, TransactionOutput},
     util::Saveable,
-    INITIAL_REWARD,
 };
 use uuid::Uuid;
 
@@ -22,7 +22,7 @@ fn main() {
         vec![],
         vec![TransactionOutput {
             unique_id: Uuid::new_v4(),
-            value: INITIAL_REWARD * 10u64.pow(8),
+            value: ChainParams::mainnet().block_reward(0),
             pubkey: private_key.public_key(),
         }],
     );
@@ -12,24 +12,28 @@ use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResu
 pub struct Signature(pub ECDSASignature<Secp256k1>);
 
 impl Signature {
-    // sign a crate::types::TransactionOutput from its sha256 hash
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    // sign a sha256 hash, e.g. a transaction's signature hash
+    pub fn sign_hash(hash: &Hash, private_key: &PrivateKey) -> Self {
         let signing_key = &private_key.0;
-        let signature: ECDSASignature<Secp256k1> = signing_key.sign(&output_hash.as_bytes());
+        let signature: ECDSASignature<Secp256k1> = signing_key.sign(&hash.as_bytes());
         Signature(signature)
     }
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+    pub fn verify(&self, hash: &Hash, public_key: &PublicKey) -> bool {
+        public_key.0.verify(&hash.as_bytes(), &self.0).is_ok()
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct PublicKey(VerifyingKey<Secp256k1>);
 
+// so keys can index maps, equal keys have equal encodings
+impl std::hash::Hash for PublicKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_encoded_point(true).as_bytes().hash(state);
+    }
+}
+
 impl Saveable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
         // read PEM-encoded public key into string
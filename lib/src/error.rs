@@ -1,6 +1,9 @@
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+use crate::{sha256::Hash, types::Amount, U256};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum BtcError {
     #[error("Invalid transaction")]
     InvalidTransaction,
@@ -22,6 +25,107 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+
+    // block rules
+    #[error("Block has no transactions")]
+    EmptyBlock,
+    #[error("Block {hash} is not the genesis block")]
+    NotGenesisBlock { hash: Hash },
+    #[error("Block {hash} is already known")]
+    DuplicateBlock { hash: Hash },
+    #[error("Parent block {prev_block_hash} is unknown")]
+    UnknownParentBlock { prev_block_hash: Hash },
+    #[error("Previous block hash is {actual}, expected {expected}")]
+    PrevBlockHashMismatch { expected: Hash, actual: Hash },
+    #[error("Block header hash {hash} does not match its target")]
+    TargetNotMet { hash: Hash },
+    #[error("Block target is {actual}, expected {expected}")]
+    UnexpectedTarget { expected: U256, actual: U256 },
+    #[error("Block forks {depth} blocks below the tip, deeper than a reorganization may go")]
+    ForkTooDeep { depth: usize },
+    #[error("Block timestamp {timestamp} is not after the previous block's {prev_timestamp}")]
+    TimestampTooOld {
+        timestamp: DateTime<Utc>,
+        prev_timestamp: DateTime<Utc>,
+    },
+    #[error("Transaction {tx_index} in block is invalid: {source}")]
+    InvalidBlockTransaction {
+        tx_index: usize,
+        source: Box<BtcError>,
+    },
+
+    // transaction rules
+    #[error("Transaction has no inputs")]
+    NoInputs,
+    #[error("Coinbase transaction has inputs")]
+    CoinbaseHasInputs,
+    #[error("Coinbase transaction has no outputs")]
+    CoinbaseWithoutOutputs,
+    #[error("Coinbase pays {actual}, expected {expected}")]
+    CoinbaseValueMismatch { expected: Amount, actual: Amount },
+    #[error("Input {input_index} spends unknown output {output_hash}")]
+    UnknownOutput {
+        input_index: usize,
+        output_hash: Hash,
+    },
+    #[error("Input {input_index} spends output {output_hash} which is already spent")]
+    DoubleSpend {
+        input_index: usize,
+        output_hash: Hash,
+    },
+    #[error("Input {input_index} has an invalid signature")]
+    InvalidInputSignature { input_index: usize },
+    #[error("Output {output_hash} is duplicated")]
+    DuplicateOutput { output_hash: Hash },
+    #[error("Outputs are worth {output_value}, more than the inputs' {input_value}")]
+    OutputsExceedInputs {
+        input_value: Amount,
+        output_value: Amount,
+    },
+    #[error("Transaction {hash} is already in the mempool")]
+    DuplicateTransaction { hash: Hash },
+    #[error("Replacement pays a lower fee rate than transaction {replaced}")]
+    ReplacementFeeRateTooLow { replaced: Hash },
+    #[error("Replacement spends an output of transaction {replaced}, which it replaces")]
+    ReplacementSpendsReplaced { replaced: Hash },
+    #[error("Replacement pays {actual} in fees, at least {required} is required")]
+    InsufficientReplacementFee { required: Amount, actual: Amount },
+    #[error("Transaction pays {actual} in fees, the mempool requires at least {required}")]
+    FeeTooLow { required: Amount, actual: Amount },
+    #[error("Mempool is full")]
+    MempoolFull,
+    #[error("Amount is negative or exceeds the total supply")]
+    AmountOutOfRange,
+}
+
+impl BtcError {
+    // the peer that sent the block or transaction broke the rules, as opposed
+    // to sending something we already have, can't connect yet or don't
+    // accept under our own mempool policy
+    pub fn is_misbehavior(&self) -> bool {
+        !matches!(
+            self,
+            BtcError::DuplicateBlock { .. }
+                | BtcError::UnknownParentBlock { .. }
+                | BtcError::PrevBlockHashMismatch { .. }
+                | BtcError::ForkTooDeep { .. }
+                | BtcError::DuplicateTransaction { .. }
+                | BtcError::UnknownOutput { .. }
+                | BtcError::ReplacementFeeRateTooLow { .. }
+                | BtcError::ReplacementSpendsReplaced { .. }
+                | BtcError::InsufficientReplacementFee { .. }
+                | BtcError::FeeTooLow { .. }
+                | BtcError::MempoolFull
+        )
+    }
+
+    // attach the index of the offending transaction within its block
+    pub fn in_transaction(self, tx_index: usize) -> Self {
+        BtcError::InvalidBlockTransaction {
+            tx_index,
+            source: Box::new(self),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
@@ -1,6 +1,7 @@
 pub mod crypto;
 pub mod error;
 pub mod network;
+pub mod params;
 pub mod sha256;
 pub mod types;
 pub mod util;
@@ -8,33 +9,33 @@ pub mod util;
 use serde::{Deserialize, Serialize};
 use uint::construct_uint;
 
-construct_uint! {
-    // Construct an unsigned 256-bit integer
-    // consisting of 4 x 64-bit words
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+pub use u256::U256;
+
+// the expanded code trips clippy::manual_div_ceil
+#[allow(clippy::manual_div_ceil)]
+mod u256 {
+    use super::*;
+
+    construct_uint! {
+        // Construct an unsigned 256-bit integer
+        // consisting of 4 x 64-bit words
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
 }
 
-// initial reward in bitcoin - multiply by 10^8 to get satoshis
-pub const INITIAL_REWARD: u64 = 50;
-// halving interval in blocks
-pub const HALVING_INTERVAL: u64 = 210;
-// ideal block time in seconds
-pub const IDEAL_BLOCK_TIME: u64 = 10;
-
-//minimum target
-pub const MIN_TARGET: U256 = U256([
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0x0000_FFFF_FFFF_FFFF,
-]);
-
-// difficulty update in blocks
-pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
-
-// max mempool transaction in secs
-pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
-
-// max transactions allowed in a block
-pub const BLOCK_TRANSACTION_CAP: usize= 20;
+// total supply in satoshis, the sum of every mainnet block reward.
+// no amount on any network may exceed it
+pub const MAX_MONEY: u64 = 2_100_000_000_000;
+
+// default max serialized size of all mempool transactions in bytes
+pub const MAX_MEMPOOL_SIZE: usize = 5_000_000;
+
+// default minimum fee rate in satoshis per byte for mempool transactions
+pub const MIN_RELAY_FEE_RATE: u64 = 1;
+
+// how many times min relay fee rate a full mempool asks for
+pub const MEMPOOL_FULL_FEE_MULTIPLIER: u64 = 10;
+
+// minimum fee increase in satoshis for a transaction replacing mempool transactions
+pub const MIN_REPLACEMENT_FEE_BUMP: u64 = 1_000;
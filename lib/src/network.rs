@@ -1,20 +1,164 @@
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
     crypto::PublicKey,
-    types::{Block, Transaction, TransactionOutput},
+    params::{ChainParams, Network},
+    sha256::Hash,
+    types::{Block, BlockHeader, Transaction, TransactionOutput},
 };
+use sha256::digest;
 use std::io::{Error as IoError, Read, Write};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+mod client;
+pub use client::{Client, ClientError};
+
+// version of the message protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 2;
+// oldest protocol version we still talk to.
+// version 2 fetches blocks by hash and syncs headers first
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+// service flags advertised in Version
+// the peer keeps the full chain and serves blocks
+pub const SERVICE_FULL_NODE: u64 = 1;
+
+// frame layout: network magic (4 bytes) | message kind (1 byte) |
+// payload length (4 bytes, big endian) | checksum (4 bytes) | CBOR Envelope.
+// the checksum is the first 4 bytes of the payload's sha256
+const FRAME_HEADER_SIZE: usize = 13;
+
+// payload size limits by message kind, checked before the payload is read
+pub const MAX_CONTROL_PAYLOAD_SIZE: usize = 4 * 1024;
+pub const MAX_TRANSACTION_PAYLOAD_SIZE: usize = 256 * 1024;
+pub const MAX_BLOCK_PAYLOAD_SIZE: usize = 8 * 1024 * 1024;
+pub const MAX_LIST_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+// most headers sent in reply to a single GetHeaders
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+
+// what a peer tells about itself when connecting
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Version {
+    pub protocol_version: u32,
+    // identifies the chain, peers on another chain are disconnected
+    pub genesis_hash: Hash,
+    pub best_height: u64,
+    pub user_agent: String,
+    pub services: u64,
+    // random per process, so a node can tell it connected to itself
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+impl Version {
+    pub fn new(params: &ChainParams, best_height: u64, user_agent: String, services: u64) -> Self {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            genesis_hash: params.genesis_block().hash(),
+            best_height,
+            user_agent,
+            services,
+            nonce: rand::random(),
+        }
+    }
+
+    // check if a peer announcing this version can talk to us
+    pub fn check(&self, params: &ChainParams) -> Result<(), HandshakeError> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion {
+                version: self.protocol_version,
+            });
+        }
+        let genesis_hash = params.genesis_block().hash();
+        if self.genesis_hash != genesis_hash {
+            return Err(HandshakeError::WrongChain {
+                expected: genesis_hash,
+                actual: self.genesis_hash,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("connection failed: {0}")]
+    Io(#[from] IoError),
+    #[error("failed to encode message: {0}")]
+    Encode(#[from] ciborium::ser::Error<IoError>),
+    #[error("frame is from another network, magic {}", hex::encode(.0))]
+    WrongMagic([u8; 4]),
+    #[error("unknown message kind {0}")]
+    UnknownKind(u8),
+    #[error("message of kind {kind} has a {size} byte payload, at most {max} are allowed")]
+    TooLarge { kind: u8, size: usize, max: usize },
+    #[error("frame checksum does not match its payload")]
+    BadChecksum,
+    #[error("frame payload is not a valid message: {0}")]
+    Malformed(ciborium::de::Error<IoError>),
+    #[error("frame header says kind {header} but the payload is kind {payload}")]
+    KindMismatch { header: u8, payload: u8 },
+}
+
+impl FrameError {
+    // the peer sent a frame no honest peer would send, as opposed to the
+    // connection failing or the peer being on another network
+    pub fn is_misbehavior(&self) -> bool {
+        matches!(
+            self,
+            FrameError::UnknownKind(_)
+                | FrameError::TooLarge { .. }
+                | FrameError::BadChecksum
+                | FrameError::Malformed(_)
+                | FrameError::KindMismatch { .. }
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error("peer sent another message instead of completing the handshake")]
+    UnexpectedMessage,
+    #[error("peer speaks unsupported protocol version {version}")]
+    UnsupportedVersion { version: u32 },
+    #[error("peer is on the chain with genesis {actual}, expected {expected}")]
+    WrongChain { expected: Hash, actual: Hash },
+}
+
+impl HandshakeError {
+    pub fn is_misbehavior(&self) -> bool {
+        match self {
+            HandshakeError::Frame(e) => e.is_misbehavior(),
+            HandshakeError::UnexpectedMessage => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
+    /// first message on every connection, sent by both sides
+    Version(Version),
+    /// acknowledges the peer's Version, completing the handshake
+    VerAck,
     /// fetch all utxos belonging to a public key
     FetchUTXOs(PublicKey),
-    /// utxos belonging to a public key. Bool determines if marked
+    /// utxos belonging to a public key. Bool determines if a mempool
+    /// transaction already spends it
     UTXOs(Vec<(TransactionOutput, bool)>),
-    /// send a transaction to the network
+    /// send a transaction to the network, answered with TransactionStatus
     SubmitTransaction(Transaction),
+    /// This is the response to SubmitTransaction, with the reason the
+    /// transaction was rejected
+    TransactionStatus(Result<(), String>),
+    /// Ask a node for the fee rate its mempool currently asks for
+    FetchFeeRate,
+    /// This is the response to FetchFeeRate, in satoshis per 1000 bytes
+    FeeRate(u64),
     /// Broadcast a new transaction to other nodes
     NewTransaction(Transaction),
     /// Ask the node to prepare the optimal block template
@@ -39,13 +183,142 @@ pub enum Message {
     AskDifference(u32),
     /// This is the response to AskDifference
     Difference(i32),
-    /// Ask a node to send a block with the specified height
-    FetchBlock(usize),
+    /// Ask a node to send the block with the specified hash,
+    /// answered with NewBlock or NotFound
+    FetchBlock(Hash),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
+    /// Ask a node for the headers of its active chain following the
+    /// first block it knows from a locator, see HeaderChain::locator
+    GetHeaders(Vec<Hash>),
+    /// This is the response to GetHeaders, at most MAX_HEADERS_PER_MESSAGE.
+    /// fewer means the node has no more
+    Headers(Vec<BlockHeader>),
+    /// the node doesn't have the requested block
+    NotFound(Hash),
+    /// announce blocks and transactions the sender has accepted
+    Inv(Vec<InvItem>),
+    /// ask for announced items the receiver doesn't have yet,
+    /// each is sent back as NewBlock or NewTransaction
+    GetData(Vec<InvItem>),
+}
+
+// a block or transaction announced by hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum InvItem {
+    Block(Hash),
+    Transaction(Hash),
 }
 
 impl Message {
+    // identifies the message in the frame header
+    pub fn kind(&self) -> u8 {
+        use Message::*;
+        match self {
+            Version(_) => 0,
+            VerAck => 1,
+            FetchUTXOs(_) => 2,
+            UTXOs(_) => 3,
+            SubmitTransaction(_) => 4,
+            NewTransaction(_) => 5,
+            FetchTemplate(_) => 6,
+            Template(_) => 7,
+            ValidateTemplate(_) => 8,
+            TemplateValidity(_) => 9,
+            SubmitTemplate(_) => 10,
+            DiscoverNodes => 11,
+            NodeList(_) => 12,
+            AskDifference(_) => 13,
+            Difference(_) => 14,
+            FetchBlock(_) => 15,
+            NewBlock(_) => 16,
+            GetHeaders(_) => 17,
+            Headers(_) => 18,
+            NotFound(_) => 19,
+            Inv(_) => 20,
+            GetData(_) => 21,
+            TransactionStatus(_) => 22,
+            FetchFeeRate => 23,
+            FeeRate(_) => 24,
+        }
+    }
+
+    // largest payload allowed for a message kind, None if the kind is unknown
+    pub fn max_payload_size(kind: u8) -> Option<usize> {
+        match kind {
+            // handshake and requests
+            0 | 1 | 2 | 6 | 9 | 11 | 13 | 14 | 15 | 17 | 19 | 22 | 23 | 24 => {
+                Some(MAX_CONTROL_PAYLOAD_SIZE)
+            }
+            // transactions
+            4 | 5 => Some(MAX_TRANSACTION_PAYLOAD_SIZE),
+            // blocks
+            7 | 8 | 10 | 16 => Some(MAX_BLOCK_PAYLOAD_SIZE),
+            // utxo, node, header and inventory lists
+            3 | 12 | 18 | 20 | 21 => Some(MAX_LIST_PAYLOAD_SIZE),
+            _ => None,
+        }
+    }
+
+    // exchange Version and VerAck with a freshly connected peer. both sides
+    // send their Version first, so it works the same for either end.
+    // returns the peer's Version
+    pub async fn handshake(
+        params: &ChainParams,
+        version: Version,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ) -> Result<Version, HandshakeError> {
+        let network = params.network;
+        Envelope::Unsolicited(Message::Version(version))
+            .send_async(network, stream)
+            .await?;
+
+        let peer_version = match Envelope::receive_async(network, stream).await? {
+            Envelope::Unsolicited(Message::Version(peer_version)) => peer_version,
+            _ => return Err(HandshakeError::UnexpectedMessage),
+        };
+        peer_version.check(params)?;
+
+        Envelope::Unsolicited(Message::VerAck)
+            .send_async(network, stream)
+            .await?;
+        match Envelope::receive_async(network, stream).await? {
+            Envelope::Unsolicited(Message::VerAck) => Ok(peer_version),
+            _ => Err(HandshakeError::UnexpectedMessage),
+        }
+    }
+}
+
+// what every frame carries, so replies can be matched to their requests
+// even when other messages arrive in between
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Envelope {
+    /// a message expecting a reply with the same id.
+    /// ids are picked by the sender and only unique per connection
+    Request { id: u64, message: Message },
+    /// the reply to the peer's request with this id
+    Response { id: u64, message: Message },
+    /// a message nobody asked for, like broadcasts and the handshake
+    Unsolicited(Message),
+}
+
+impl Envelope {
+    pub fn message(&self) -> &Message {
+        match self {
+            Envelope::Request { message, .. }
+            | Envelope::Response { message, .. }
+            | Envelope::Unsolicited(message) => message,
+        }
+    }
+
+    pub fn into_message(self) -> Message {
+        match self {
+            Envelope::Request { message, .. }
+            | Envelope::Response { message, .. }
+            | Envelope::Unsolicited(message) => message,
+        }
+    }
+
     pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
         let mut bytes = Vec::new();
         ciborium::into_writer(self, &mut bytes)?;
@@ -56,42 +329,108 @@ impl Message {
         ciborium::from_reader(data)
     }
 
-    pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes())?;
-        stream.write_all(&bytes)?;
+    // the whole frame for this envelope, see FRAME_HEADER_SIZE for the layout.
+    // the header kind and size limit are those of the wrapped message
+    fn to_frame(&self, network: Network) -> Result<Vec<u8>, FrameError> {
+        let payload = self.encode()?;
+        let kind = self.message().kind();
+        let max = Message::max_payload_size(kind).expect("BUG: message kind has no size limit");
+        if payload.len() > max {
+            return Err(FrameError::TooLarge {
+                kind,
+                size: payload.len(),
+                max,
+            });
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&network.magic());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    // check the header before anything is allocated for the payload.
+    // returns the message kind, payload length and checksum
+    fn parse_header(
+        network: Network,
+        header: [u8; FRAME_HEADER_SIZE],
+    ) -> Result<(u8, usize, [u8; 4]), FrameError> {
+        let magic: [u8; 4] = header[0..4].try_into().unwrap();
+        if magic != network.magic() {
+            return Err(FrameError::WrongMagic(magic));
+        }
+
+        let kind = header[4];
+        let max = Message::max_payload_size(kind).ok_or(FrameError::UnknownKind(kind))?;
+        let size = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+        if size > max {
+            return Err(FrameError::TooLarge { kind, size, max });
+        }
+
+        let checksum = header[9..13].try_into().unwrap();
+        Ok((kind, size, checksum))
+    }
+
+    fn from_payload(
+        kind: u8,
+        expected_checksum: [u8; 4],
+        payload: &[u8],
+    ) -> Result<Self, FrameError> {
+        if checksum(payload) != expected_checksum {
+            return Err(FrameError::BadChecksum);
+        }
+        let envelope = Self::decode(payload).map_err(FrameError::Malformed)?;
+        let payload_kind = envelope.message().kind();
+        if payload_kind != kind {
+            return Err(FrameError::KindMismatch {
+                header: kind,
+                payload: payload_kind,
+            });
+        }
+        Ok(envelope)
+    }
+
+    pub fn send(&self, network: Network, stream: &mut impl Write) -> Result<(), FrameError> {
+        stream.write_all(&self.to_frame(network)?)?;
         Ok(())
     }
 
-    pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
-        stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data)?;
-        Self::decode(&data)
+    pub fn receive(network: Network, stream: &mut impl Read) -> Result<Self, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header)?;
+        let (kind, size, checksum) = Self::parse_header(network, header)?;
+        let mut payload = vec![0u8; size];
+        stream.read_exact(&mut payload)?;
+        Self::from_payload(kind, checksum, &payload)
     }
 
     pub async fn send_async(
         &self,
+        network: Network,
         stream: &mut (impl AsyncWrite + Unpin),
-    ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes()).await?;
-        stream.write_all(&bytes).await?;
+    ) -> Result<(), FrameError> {
+        stream.write_all(&self.to_frame(network)?).await?;
         Ok(())
     }
 
     pub async fn receive_async(
+        network: Network,
         stream: &mut (impl AsyncRead + Unpin),
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
-        stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data).await?;
-        Self::decode(&data)
+    ) -> Result<Self, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header).await?;
+        let (kind, size, checksum) = Self::parse_header(network, header)?;
+        let mut payload = vec![0u8; size];
+        stream.read_exact(&mut payload).await?;
+        Self::from_payload(kind, checksum, &payload)
     }
 }
+
+// first 4 bytes of the payload's sha256
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = hex::decode(digest(payload)).expect("BUG: sha256 digest is not hex");
+    hash[..4].try_into().unwrap()
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    io::Error as IoError,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use thiserror::Error;
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+    sync::{mpsc, oneshot, Mutex as AsyncMutex},
+    task::JoinHandle,
+    time::{timeout, Duration},
+};
+
+use super::{Envelope, FrameError, HandshakeError, Message, Version};
+use crate::params::{ChainParams, Network};
+
+// requests waiting for their reply, by id. None once the connection is gone
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Message>>>>>;
+
+// how long to wait for the connection and handshake to complete
+pub const CONNECT_TIMEOUT_SECS: u64 = 10;
+// how long to wait for the reply to a request
+pub const REQUEST_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("failed to connect: {0}")]
+    Connect(#[from] IoError),
+    #[error("handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error("connection closed before the reply arrived")]
+    Disconnected,
+    #[error("timed out")]
+    Timeout,
+}
+
+// a connection to a node with any number of requests in flight.
+// a background task reads every frame, hands each reply to the request with
+// the same id and sends unsolicited messages, like broadcasts, to the
+// channel returned by connect
+pub struct Client {
+    network: Network,
+    peer_addr: SocketAddr,
+    peer_version: Version,
+    writer: AsyncMutex<OwnedWriteHalf>,
+    pending: Pending,
+    next_id: Arc<AtomicU64>,
+    reader: JoinHandle<()>,
+}
+
+impl Client {
+    // connect and complete the handshake
+    pub async fn connect(
+        address: impl ToSocketAddrs,
+        params: &ChainParams,
+        version: Version,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<Message>), ClientError> {
+        let (stream, peer_addr, peer_version) =
+            timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS), async {
+                let mut stream = TcpStream::connect(address).await?;
+                let peer_addr = stream.peer_addr()?;
+                let peer_version = Message::handshake(params, version, &mut stream).await?;
+                Ok::<_, ClientError>((stream, peer_addr, peer_version))
+            })
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+
+        let (reader, writer) = stream.into_split();
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let next_id = Arc::new(AtomicU64::new(0));
+        let (unsolicited_sender, unsolicited_receiver) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_frames(
+            params.network,
+            reader,
+            pending.clone(),
+            next_id.clone(),
+            unsolicited_sender,
+        ));
+
+        let client = Client {
+            network: params.network,
+            peer_addr,
+            peer_version,
+            writer: AsyncMutex::new(writer),
+            pending,
+            next_id,
+            reader,
+        };
+        Ok((client, unsolicited_receiver))
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    // what the peer announced in the handshake
+    pub fn peer_version(&self) -> &Version {
+        &self.peer_version
+    }
+
+    // false once the connection failed or the peer broke the protocol
+    pub fn is_connected(&self) -> bool {
+        !self.reader.is_finished()
+    }
+
+    // send a request and wait for its reply, giving up after
+    // REQUEST_TIMEOUT_SECS
+    pub async fn request(&self, message: Message) -> Result<Message, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or(ClientError::Disconnected)?
+            .insert(id, sender);
+
+        let result = timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), async {
+            self.write(&Envelope::Request { id, message }).await?;
+            receiver.await.map_err(|_| ClientError::Disconnected)
+        })
+        .await
+        .unwrap_or(Err(ClientError::Timeout));
+        if result.is_err() {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+        }
+        result
+    }
+
+    // send a message that gets no reply
+    pub async fn send(&self, message: Message) -> Result<(), ClientError> {
+        self.write(&Envelope::Unsolicited(message)).await
+    }
+
+    async fn write(&self, envelope: &Envelope) -> Result<(), ClientError> {
+        let mut writer = self.writer.lock().await;
+        envelope.send_async(self.network, &mut *writer).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// runs until the connection fails or the peer breaks the protocol
+async fn read_frames(
+    network: Network,
+    mut reader: OwnedReadHalf,
+    pending: Pending,
+    next_id: Arc<AtomicU64>,
+    unsolicited: mpsc::UnboundedSender<Message>,
+) {
+    while let Ok(envelope) = Envelope::receive_async(network, &mut reader).await {
+        match envelope {
+            Envelope::Response { id, message } => {
+                let sender = pending
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .and_then(|pending| pending.remove(&id));
+                let Some(sender) = sender else {
+                    // a late reply to a request that timed out is dropped,
+                    // a reply to something we never asked ends the connection
+                    if id < next_id.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    break;
+                };
+                // the requester may have given up waiting
+                let _ = sender.send(message);
+            }
+            Envelope::Unsolicited(message) => {
+                // nobody may be listening for broadcasts
+                let _ = unsolicited.send(message);
+            }
+            // we don't serve requests
+            Envelope::Request { .. } => break,
+        }
+    }
+    // dropping the senders fails every request still waiting
+    pending.lock().unwrap().take();
+}
@@ -0,0 +1,244 @@
+use std::{fmt, str::FromStr};
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    crypto::PublicKey,
+    sha256::Hash,
+    types::{Amount, Block, BlockHeader, Transaction, TransactionOutput},
+    util::{MerkleRoot, Saveable},
+    U256,
+};
+
+// the x coordinate is sha256("rsbtc genesis 0"), so nobody knows the private key
+const GENESIS_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MFYwEAYHKoZIzj0CAQYFK4EEAAoDQgAE0xm79vXvx+lBappZckFDuOw647/e4Y+h
+EFdajZginr9ZaoxyUP2cP8q0m4odEKRzpo97IYa1grdvWKOK84sRFg==
+-----END PUBLIC KEY-----
+";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    /// local network for tests, blocks are mined instantly
+    Regtest,
+}
+
+impl Network {
+    // first bytes of every network frame, so peers on different networks
+    // can't mistake each other's messages
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => *b"rsbt",
+            Network::Testnet => *b"rstn",
+            Network::Regtest => *b"rsrg",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!(
+                "unknown network {s}, expected mainnet, testnet or regtest"
+            )),
+        }
+    }
+}
+
+// consensus rules of a network. every node on a network must use the same ones
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    // port nodes listen on unless told otherwise
+    pub default_port: u16,
+    // port of the JSON-RPC interface on localhost unless told otherwise
+    pub default_rpc_port: u16,
+    // initial reward in bitcoin - multiply by 10^8 to get satoshis
+    pub initial_reward: u64,
+    // halving interval in blocks
+    pub halving_interval: u64,
+    // ideal block time in seconds
+    pub ideal_block_time: u64,
+    // easiest target a block may have
+    pub min_target: U256,
+    // difficulty update in blocks
+    pub difficulty_update_interval: u64,
+    // keep the target at min_target forever
+    pub no_retargeting: bool,
+    // max mempool transaction age in secs
+    pub max_mempool_transaction_age: u64,
+    // max transactions allowed in a block, not counting the coinbase
+    pub block_transaction_cap: usize,
+    // blocks of the active chain a reorganization may replace, side blocks
+    // forking deeper below the tip are rejected
+    pub max_reorg_depth: usize,
+    // most blocks kept on side branches, the lowest are dropped first
+    pub max_side_blocks: usize,
+    // the genesis block nonce was mined for this exact timestamp
+    pub genesis_timestamp: i64,
+    pub genesis_nonce: u64,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            default_port: 9000,
+            default_rpc_port: 9001,
+            initial_reward: 50,
+            halving_interval: 210,
+            ideal_block_time: 10,
+            min_target: U256([
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0x0000_FFFF_FFFF_FFFF,
+            ]),
+            difficulty_update_interval: 50,
+            no_retargeting: false,
+            max_mempool_transaction_age: 600,
+            block_transaction_cap: 20,
+            max_reorg_depth: 100,
+            max_side_blocks: 1_000,
+            genesis_timestamp: 1_737_590_400,
+            genesis_nonce: 30_562,
+        }
+    }
+
+    // same rules as mainnet on a separate chain
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            default_port: 19000,
+            default_rpc_port: 19001,
+            genesis_timestamp: 1_737_676_800,
+            genesis_nonce: 206_372,
+            ..Self::mainnet()
+        }
+    }
+
+    // any hash meets the target, so blocks are mined instantly
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: Network::Regtest,
+            default_port: 19444,
+            default_rpc_port: 19445,
+            halving_interval: 150,
+            ideal_block_time: 1,
+            min_target: U256::MAX,
+            no_retargeting: true,
+            genesis_timestamp: 1_737_763_200,
+            genesis_nonce: 0,
+            ..Self::mainnet()
+        }
+    }
+
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::mainnet(),
+            Network::Testnet => Self::testnet(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    // reward for mining the block at block_height, halving every halving_interval blocks
+    pub fn block_reward(&self, block_height: u64) -> Amount {
+        let halvings = block_height / self.halving_interval;
+        let reward = u32::try_from(halvings)
+            .ok()
+            .and_then(|halvings| (self.initial_reward * 10u64.pow(8)).checked_shr(halvings))
+            .unwrap_or(0);
+        Amount::from_sat(reward).expect("BUG: block reward exceeds MAX_MONEY")
+    }
+
+    // target for the block following a chain of chain_len blocks, given the
+    // target of its last block. timestamp_at returns the timestamp of the
+    // block at a height
+    pub fn next_target(
+        &self,
+        target: U256,
+        chain_len: usize,
+        timestamp_at: impl Fn(usize) -> DateTime<Utc>,
+    ) -> U256 {
+        if chain_len == 0 || self.no_retargeting {
+            return target;
+        }
+        let interval = self.difficulty_update_interval;
+        if !chain_len.is_multiple_of(interval as usize) {
+            return target;
+        }
+        let start_time = timestamp_at(chain_len - interval as usize);
+        let end_time = timestamp_at(chain_len - 1);
+        let time_diff = end_time - start_time;
+        let time_diff_seconds = time_diff.num_seconds();
+        let target_seconds = self.ideal_block_time * interval;
+        let new_target = BigDecimal::parse_bytes(target.to_string().as_bytes(), 10)
+            .expect("Bug impossible")
+            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
+        let new_target_str = new_target
+            .to_string()
+            .split('.')
+            .next()
+            .expect("Bug: expected a decimal point")
+            .to_owned();
+        let new_target = U256::from_str_radix(&new_target_str, 10).expect("BUG: impossible");
+        let new_target = if new_target < target / 4 {
+            target / 4
+        } else if new_target > target * 4 {
+            target * 4
+        } else {
+            new_target
+        };
+        new_target.min(self.min_target)
+    }
+
+    // the hard-coded first block every chain of this network starts from
+    pub fn genesis_block(&self) -> Block {
+        let pubkey = PublicKey::load(GENESIS_PUBLIC_KEY.as_bytes())
+            .expect("BUG: invalid genesis public key");
+        let transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: self.block_reward(0),
+                unique_id: Uuid::nil(),
+                pubkey,
+            }],
+        )];
+        let timestamp = DateTime::from_timestamp(self.genesis_timestamp, 0)
+            .expect("BUG: invalid genesis timestamp");
+        let header = BlockHeader::new(
+            timestamp,
+            self.genesis_nonce,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            self.min_target,
+        );
+        Block::new(header, transactions)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
@@ -1,14 +1,15 @@
 use std::fmt::{self};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 use sha256::digest;
 
 use crate::U256;
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Hash(U256);
 
 impl Hash {
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
 
@@ -35,9 +36,7 @@ impl Hash {
 
     // convert to bytes
     pub fn as_bytes(&self) -> [u8; 32] {
-        let bytes: Vec<u8> = vec![0; 32];
-        self.0.to_little_endian();
-        bytes.as_slice().try_into().unwrap()
+        self.0.to_little_endian()
     }
 }
 
@@ -46,3 +45,28 @@ impl fmt::Display for Hash {
         write!(f, "{:x}", self.0)
     }
 }
+
+// hex in human-readable formats like JSON, the plain U256 in CBOR
+// so that hashes of hashes stay the same
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&format!("{:064x}", self.0))
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            U256::from_str_radix(&hex, 16)
+                .map(Hash)
+                .map_err(|_| D::Error::custom(format!("invalid hash: {hex}")))
+        } else {
+            U256::deserialize(deserializer).map(Hash)
+        }
+    }
+}
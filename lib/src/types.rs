@@ -1,7 +1,15 @@
+mod amount;
 mod block;
 mod blockchain;
+mod header_chain;
+mod index;
+mod mempool;
 mod transaction;
 
+pub use amount::Amount;
 pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
+pub use blockchain::{BlockOutcome, Blockchain};
+pub use header_chain::HeaderChain;
+pub use index::{ChainIndex, OutputRecord, TxLocation};
+pub use mempool::{Mempool, MempoolEntry, MempoolSnapshot};
+pub use transaction::{SigHashType, Transaction, TransactionInput, TransactionOutput};
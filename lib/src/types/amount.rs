@@ -0,0 +1,70 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BtcError, Result};
+
+// an amount of satoshis, never more than MAX_MONEY.
+// all arithmetic is checked and fails with a BtcError instead of
+// panicking or wrapping around
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(crate::MAX_MONEY);
+
+    pub fn from_sat(satoshis: u64) -> Result<Self> {
+        if satoshis > crate::MAX_MONEY {
+            return Err(BtcError::AmountOutOfRange);
+        }
+        Ok(Amount(satoshis))
+    }
+
+    pub fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Result<Self> {
+        self.0
+            .checked_add(rhs.0)
+            .ok_or(BtcError::AmountOutOfRange)
+            .and_then(Amount::from_sat)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Result<Self> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Amount)
+            .ok_or(BtcError::AmountOutOfRange)
+    }
+
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Amount>) -> Result<Self> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+impl TryFrom<u64> for Amount {
+    type Error = BtcError;
+
+    fn try_from(satoshis: u64) -> Result<Self> {
+        Amount::from_sat(satoshis)
+    }
+}
+
+impl From<Amount> for u64 {
+    fn from(amount: Amount) -> Self {
+        amount.0
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
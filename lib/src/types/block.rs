@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
 };
 
@@ -13,7 +13,10 @@ use crate::{
     U256,
 };
 
-use super::transaction::{Transaction, TransactionOutput};
+use super::{
+    amount::Amount,
+    transaction::{Transaction, TransactionOutput},
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Block {
@@ -29,79 +32,99 @@ impl Block {
         }
     }
 
+    // the header commits to the transactions through the merkle root, so
+    // blocks can be identified and linked by their headers alone
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
     }
 
-    pub fn calculate_miner_fees(
-        &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+    pub fn calculate_miner_fees(&self, utxos: &HashMap<Hash, TransactionOutput>) -> Result<Amount> {
+        let mut spent: HashSet<Hash> = HashSet::new();
+        // outputs created earlier in this block can be spent by later transactions
+        let mut created: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut fees = Amount::ZERO;
 
         //check every transaction after coinbase
-        for transaction in self.transactions.iter().skip(1) {
-            for input in &transaction.inputs {
+        for (tx_index, transaction) in self.transactions.iter().enumerate().skip(1) {
+            let mut input_value = Amount::ZERO;
+            for (input_index, input) in transaction.inputs.iter().enumerate() {
+                let output_hash = input.prev_transaction_output_hash;
                 //inputs doesnt contain the output values, so we have to match inputs to outputs
-                let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
+                let Some(prev_output) = utxos.get(&output_hash).or(created.get(&output_hash))
+                else {
+                    return Err(BtcError::UnknownOutput {
+                        input_index,
+                        output_hash,
+                    }
+                    .in_transaction(tx_index));
+                };
+
+                if !spent.insert(output_hash) {
+                    return Err(BtcError::DoubleSpend {
+                        input_index,
+                        output_hash,
+                    }
+                    .in_transaction(tx_index));
                 }
 
-                let prev_output = prev_output.unwrap();
+                input_value = input_value
+                    .checked_add(prev_output.value)
+                    .map_err(|e| e.in_transaction(tx_index))?;
+            }
 
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+            let output_value = transaction
+                .output_value()
+                .map_err(|e| e.in_transaction(tx_index))?;
+            if input_value < output_value {
+                return Err(BtcError::OutputsExceedInputs {
+                    input_value,
+                    output_value,
                 }
-
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+                .in_transaction(tx_index));
             }
+            fees = fees.checked_add(input_value.checked_sub(output_value)?)?;
 
             for output in &transaction.outputs {
-                if outputs.contains_key(&output.hash()) {
-                    return Err(BtcError::InvalidTransaction);
+                let output_hash = output.hash();
+                if created.insert(output_hash, output.clone()).is_some() {
+                    return Err(BtcError::DuplicateOutput { output_hash }.in_transaction(tx_index));
                 }
-                outputs.insert(output.hash(), output.clone());
             }
         }
 
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
-        Ok(input_value - output_value)
+        Ok(fees)
     }
 
     pub fn verify_coinbase_transaction(
         &self,
-        predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        block_reward: Amount,
+        utxos: &HashMap<Hash, TransactionOutput>,
     ) -> Result<()> {
         // coinbase tx is the first tx in the block
         let coinbase_transaction = &self.transactions[0];
 
         if !coinbase_transaction.inputs.is_empty() {
-            return Err(BtcError::InvalidTransaction);
+            return Err(BtcError::CoinbaseHasInputs.in_transaction(0));
         }
 
         if coinbase_transaction.outputs.is_empty() {
-            return Err(BtcError::InvalidTransaction);
+            return Err(BtcError::CoinbaseWithoutOutputs.in_transaction(0));
         }
 
         let miner_fees = self.calculate_miner_fees(utxos)?;
 
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
+        let expected = block_reward.checked_add(miner_fees)?;
 
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
+        let total_coinbase_outputs = coinbase_transaction
+            .output_value()
+            .map_err(|e| e.in_transaction(0))?;
 
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
+        if total_coinbase_outputs != expected {
+            return Err(BtcError::CoinbaseValueMismatch {
+                expected,
+                actual: total_coinbase_outputs,
+            }
+            .in_transaction(0));
         }
 
         Ok(())
@@ -110,56 +133,51 @@ impl Block {
     // verify all transactions in the block
     pub fn verify_transactions(
         &self,
-        predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        block_reward: Amount,
+        utxos: &HashMap<Hash, TransactionOutput>,
     ) -> Result<()> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-
         // reject empty blocks
         if self.transactions.is_empty() {
-            return Err(BtcError::InvalidBlock);
+            return Err(BtcError::EmptyBlock);
         }
 
         // verify coinbase transaction
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
-
-        for transaction in &self.transactions {
-            let mut input_value = 0;
-            let mut output_value = 0;
-
-            for input in &transaction.inputs {
-                let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
+        self.verify_coinbase_transaction(block_reward, utxos)?;
+
+        // coinbase was verified above, check every transaction after it
+        let mut spent_outputs = HashSet::new();
+        let mut created: HashMap<Hash, TransactionOutput> = HashMap::new();
+        for (tx_index, transaction) in self.transactions.iter().enumerate().skip(1) {
+            // a transaction may spend outputs of the ones before it
+            let prev_outputs: HashMap<Hash, TransactionOutput> = transaction
+                .inputs
+                .iter()
+                .filter_map(|input| {
+                    let output_hash = input.prev_transaction_output_hash;
+                    utxos
+                        .get(&output_hash)
+                        .or(created.get(&output_hash))
+                        .map(|output| (output_hash, output.clone()))
+                })
+                .collect();
+            transaction
+                .verify(&prev_outputs)
+                .map_err(|e| e.in_transaction(tx_index))?;
+
+            // prevent same block double spending
+            for (input_index, input) in transaction.inputs.iter().enumerate() {
+                let output_hash = input.prev_transaction_output_hash;
+                if !spent_outputs.insert(output_hash) {
+                    return Err(BtcError::DoubleSpend {
+                        input_index,
+                        output_hash,
+                    }
+                    .in_transaction(tx_index));
                 }
-
-                let prev_output = prev_output.unwrap();
-
-                // prevent same block double spending
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
-
-                // check if signature is valid
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
-                {
-                    return Err(BtcError::InvalidSignature);
-                }
-
-                input_value += prev_output.value;
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
             }
 
             for output in &transaction.outputs {
-                output_value += output.value;
-            }
-
-            if input_value < output_value {
-                return Err(BtcError::InvalidTransaction);
+                created.insert(output.hash(), output.clone());
             }
         }
 
@@ -207,6 +225,12 @@ impl BlockHeader {
         Hash::hash(self)
     }
 
+    // expected number of hashes needed to meet the target, i.e. 2^256 / (target + 1).
+    // 2^256 doesn't fit in a U256, so compute it as !target / (target + 1) + 1
+    pub fn work(&self) -> U256 {
+        (!self.target / self.target.saturating_add(U256::one())) + U256::one()
+    }
+
     pub fn mine(&mut self, steps: usize) -> bool {
         if self.hash().matches_target(self.target) {
             return true;
@@ -1,44 +1,92 @@
 use std::{
+    cmp::Ordering,
     collections::{HashMap, HashSet},
     io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
 };
 
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::{
+    crypto::PublicKey,
     error::{BtcError, Result},
+    params::ChainParams,
     sha256::Hash,
     util::{MerkleRoot, Saveable},
     U256,
 };
 
 use super::{
-    block::Block,
+    amount::Amount,
+    block::{Block, BlockHeader},
+    index::{ChainIndex, TxLocation},
+    mempool::{Mempool, MempoolEntry, MempoolSnapshot},
     transaction::{Transaction, TransactionOutput},
 };
 
+// the outputs a connected block spent, needed to disconnect it again
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct BlockUndo {
+    spent_outputs: Vec<(Hash, TransactionOutput)>,
+}
+
+// what adding a block did to the blockchain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// the block extends the active chain
+    Connected,
+    /// the block is stored on a side branch with no more work than the active chain
+    SideBranch,
+    /// the block's side branch has more work and replaced the active chain
+    /// above fork_height
+    Reorganized { fork_height: usize },
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Blockchain {
+    // consensus rules, files written before networks existed are mainnet
+    #[serde(default)]
+    params: ChainParams,
     blocks: Vec<Block>,
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    // height of every block of the active chain by hash, rebuilt on load
+    #[serde(skip)]
+    heights: HashMap<Hash, usize>,
+    // one undo record for every block in the active chain
+    #[serde(default)]
+    undo: Vec<BlockUndo>,
+    utxos: HashMap<Hash, TransactionOutput>,
     target: U256,
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    // blocks on branches other than the active chain, keyed by block hash
+    #[serde(default)]
+    side_blocks: HashMap<Hash, Block>,
+    #[serde(skip)]
+    mempool: Mempool,
+    // optional, rebuilt from the blocks when enabled
+    #[serde(skip)]
+    index: Option<ChainIndex>,
 }
 
 impl Blockchain {
-    pub fn new() -> Self {
+    pub fn new(params: ChainParams) -> Self {
         Blockchain {
+            target: params.min_target,
+            params,
             blocks: vec![],
+            heights: HashMap::new(),
+            undo: vec![],
             utxos: HashMap::new(),
-            target: crate::MIN_TARGET,
-            mempool: vec![],
+            side_blocks: HashMap::new(),
+            mempool: Mempool::new(),
+            index: None,
         }
     }
 
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn utxos(&self) -> &HashMap<Hash, TransactionOutput> {
         &self.utxos
     }
 
@@ -54,257 +102,674 @@ impl Blockchain {
         self.blocks.len() as u64
     }
 
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
+    // height of a block on the active chain
+    pub fn height(&self, hash: &Hash) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+
+    // a block on the active chain or a side branch
+    pub fn block(&self, hash: &Hash) -> Option<&Block> {
+        self.height(hash)
+            .map(|height| &self.blocks[height])
+            .or_else(|| self.side_blocks.get(hash))
+    }
+
+    pub fn contains_block(&self, hash: &Hash) -> bool {
+        self.block(hash).is_some()
+    }
+
+    // total work of the active chain
+    pub fn work(&self) -> U256 {
+        Self::chain_work(self.blocks.iter())
+    }
+
+    // up to max headers of the active chain following the first locator
+    // hash on it, starting from genesis if there is none
+    pub fn headers_after(&self, locator: &[Hash], max: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height(hash))
+            .map_or(0, |height| height + 1);
+        self.blocks[start..]
+            .iter()
+            .take(max)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    pub fn mempool(&self) -> &Mempool {
         &self.mempool
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
+    // build the transaction and key indexes and keep them up to date
+    pub fn enable_index(&mut self) {
+        if self.index.is_none() {
+            self.index = Some(ChainIndex::new(self.blocks.iter()));
+        }
+    }
+
+    // None unless enable_index was called
+    pub fn index(&self) -> Option<&ChainIndex> {
+        self.index.as_ref()
+    }
+
+    // a transaction on the active chain and where it is, scanning
+    // the blocks if there is no index
+    pub fn transaction(&self, hash: &Hash) -> Option<(&Transaction, TxLocation)> {
+        let location = match &self.index {
+            Some(index) => index.transaction(hash)?,
+            None => self.blocks.iter().enumerate().find_map(|(height, block)| {
+                block
+                    .transactions
+                    .iter()
+                    .position(|transaction| transaction.hash() == *hash)
+                    .map(|position| TxLocation { height, position })
+            })?,
+        };
+        let transaction = &self.blocks[location.height].transactions[location.position];
+        Some((transaction, location))
+    }
+
+    // reward for the next block to be mined
+    pub fn calculate_block_reward(&self) -> Amount {
+        self.params.block_reward(self.block_height())
+    }
+
+    pub fn add_block(&mut self, block: Block) -> Result<BlockOutcome> {
+        // blocks extending the tip are connected directly, anything
+        // else starts or extends a side branch
+        match self.blocks.last() {
+            Some(last_block) if block.header.prev_block_hash != last_block.hash() => {
+                self.add_side_block(block)
+            }
+            _ => self.connect_block(block).map(|()| BlockOutcome::Connected),
+        }
+    }
+
+    // validate a block against the current tip and append it to the active chain
+    fn connect_block(&mut self, block: Block) -> Result<()> {
         if self.blocks.is_empty() {
-            // if this is the first block, check the prev_block_hash is all zeroes
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
+            // if this is the first block, it must be the hard-coded genesis block.
+            // the hash only covers the header, the merkle root ties the
+            // transactions to it
+            let hash = block.hash();
+            if hash != self.params.genesis_block().hash() {
+                return Err(BtcError::NotGenesisBlock { hash });
+            }
+            if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+                return Err(BtcError::InvalidMerkleRoot);
             }
         } else {
             // if this is not the first block, check if the prev_block_hash is the hash of the last
             // block
             let last_block = self.blocks.last().unwrap();
 
-            if block.header.prev_block_hash != last_block.hash() {
-                println!("previous block has is wrong");
-                return Err(BtcError::InvalidBlock);
+            let last_block_hash = last_block.hash();
+            if block.header.prev_block_hash != last_block_hash {
+                return Err(BtcError::PrevBlockHashMismatch {
+                    expected: last_block_hash,
+                    actual: block.header.prev_block_hash,
+                });
+            }
+
+            // the target must follow the difficulty adjustments, otherwise
+            // a miner could pick an easier one
+            if block.header.target != self.target {
+                return Err(BtcError::UnexpectedTarget {
+                    expected: self.target,
+                    actual: block.header.target,
+                });
             }
 
             // check if the block's has is less than the target
-            if !block.header.hash().matches_target(block.header.target) {
-                println!("block has does not match target");
-                return Err(BtcError::InvalidBlock);
+            let header_hash = block.header.hash();
+            if !header_hash.matches_target(block.header.target) {
+                return Err(BtcError::TargetNotMet { hash: header_hash });
             }
 
             // check if the merkle root is correct
             let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
             if calculated_merkle_root != block.header.merkle_root {
-                println!("invalid merkle root");
                 return Err(BtcError::InvalidMerkleRoot);
             }
 
             // check if the block's timestamp is after the last blocks' timestamp
             if block.header.timestamp <= last_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+                return Err(BtcError::TimestampTooOld {
+                    timestamp: block.header.timestamp,
+                    prev_timestamp: last_block.header.timestamp,
+                });
             }
 
             // verify all the transaction in the block
-            block.verify_transactions(self.block_height(), &self.utxos)?;
+            block.verify_transactions(self.calculate_block_reward(), &self.utxos)?;
+        }
+        let undo = Self::connect_utxos(&mut self.utxos, &block)?;
+        if let Some(index) = &mut self.index {
+            index.connect_block(self.blocks.len(), &block);
         }
-        // remove the transaction from mempool that is now in the block
-        let block_transactions: HashSet<_> =
-            block.transactions.iter().map(|tx| tx.hash()).collect();
-        self.mempool
-            .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
+
+        // remove the transactions from mempool that are now in the block
+        // or that spend an output the block spent
+        self.mempool.remove_for_block(&block);
+        self.heights.insert(block.hash(), self.blocks.len());
         self.blocks.push(block);
+        self.undo.push(undo);
         self.try_adjust_target();
         Ok(())
     }
 
-    // rebuild UTXO set from the blockchain
-    pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
+    // spend the block's inputs and add its outputs to the utxo set.
+    // every input is looked up before anything is changed, so a missing
+    // output leaves the set untouched
+    fn connect_utxos(
+        utxos: &mut HashMap<Hash, TransactionOutput>,
+        block: &Block,
+    ) -> Result<BlockUndo> {
+        let mut spent_outputs = vec![];
+        let mut created: HashMap<Hash, TransactionOutput> = HashMap::new();
+        for (tx_index, transaction) in block.transactions.iter().enumerate() {
+            for (input_index, input) in transaction.inputs.iter().enumerate() {
+                let output_hash = input.prev_transaction_output_hash;
+                // spent by a later transaction in the same block, so it
+                // never reaches the utxo set
+                if created.remove(&output_hash).is_some() {
+                    continue;
                 }
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(output.hash(), (false, output.clone()));
+                let Some(output) = utxos.get(&output_hash) else {
+                    return Err(BtcError::UnknownOutput {
+                        input_index,
+                        output_hash,
+                    }
+                    .in_transaction(tx_index));
+                };
+                spent_outputs.push((output_hash, output.clone()));
+            }
+            // an output already in the utxo set would be overwritten, and
+            // lost once either copy is spent or the block is disconnected
+            for output in &transaction.outputs {
+                let output_hash = output.hash();
+                if utxos.contains_key(&output_hash)
+                    || created.insert(output_hash, output.clone()).is_some()
+                {
+                    return Err(BtcError::DuplicateOutput { output_hash }.in_transaction(tx_index));
                 }
             }
         }
+
+        for (hash, _) in &spent_outputs {
+            utxos.remove(hash);
+        }
+        utxos.extend(created);
+        Ok(BlockUndo { spent_outputs })
     }
 
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty() {
-            return;
-        }
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
-            return;
-        }
-        let start_time = self.blocks
-            [self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff = end_time - start_time;
-        let time_diff_seconds = time_diff.num_seconds();
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
-        let new_target = BigDecimal::parse_bytes(self.target.to_string().as_bytes(), 10)
-            .expect("Bug impossible")
-            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("Bug: expected a decimal point")
-            .to_owned();
-        let new_target = U256::from_str_radix(&new_target_str, 10).expect("BUG: impossible");
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
+    // remove the tip of the active chain, restoring the outputs it spent
+    fn disconnect_block(&mut self) -> Option<Block> {
+        let block = self.blocks.pop()?;
+        self.heights.remove(&block.hash());
+        let undo = self.undo.pop().expect("BUG: missing undo record");
+        if let Some(index) = &mut self.index {
+            index.disconnect_block(&block);
+        }
+
+        for transaction in &block.transactions {
+            for output in &transaction.outputs {
+                self.utxos.remove(&output.hash());
+            }
+        }
+        for (hash, output) in undo.spent_outputs {
+            self.utxos.insert(hash, output);
+        }
+        Some(block)
+    }
+
+    fn add_side_block(&mut self, block: Block) -> Result<BlockOutcome> {
+        let block_hash = block.hash();
+        if self.contains_block(&block_hash) {
+            return Err(BtcError::DuplicateBlock { hash: block_hash });
+        }
+
+        // walk back through the side branch to the fork point on the active chain
+        let prev_block_hash = block.header.prev_block_hash;
+        let mut branch = vec![];
+        let mut cursor = prev_block_hash;
+        let fork_index = loop {
+            if let Some(height) = self.height(&cursor) {
+                break height;
+            }
+            let Some(side_block) = self.side_blocks.get(&cursor) else {
+                return Err(BtcError::UnknownParentBlock { prev_block_hash });
+            };
+            branch.push(cursor);
+            cursor = side_block.header.prev_block_hash;
+        };
+        branch.reverse();
+
+        // a deep fork would make us undo most of the chain, and cheap
+        // blocks off old ones could be stored without limit
+        let depth = self.blocks.len() - 1 - fork_index;
+        if depth > self.params.max_reorg_depth {
+            return Err(BtcError::ForkTooDeep { depth });
+        }
+
+        // transactions can only be verified once the branch is connected,
+        // until then check everything that doesn't depend on the utxo set
+        let branch_block = |height: usize| match height.checked_sub(fork_index + 1) {
+            Some(idx) => &self.side_blocks[&branch[idx]],
+            None => &self.blocks[height],
+        };
+        let parent_height = fork_index + branch.len();
+        let parent = branch_block(parent_height);
+        // the target must follow the difficulty adjustments of the branch
+        let expected_target =
+            self.params
+                .next_target(parent.header.target, parent_height + 1, |height| {
+                    branch_block(height).header.timestamp
+                });
+        if block.header.target != expected_target {
+            return Err(BtcError::UnexpectedTarget {
+                expected: expected_target,
+                actual: block.header.target,
+            });
+        }
+        let header_hash = block.header.hash();
+        if !header_hash.matches_target(block.header.target) {
+            return Err(BtcError::TargetNotMet { hash: header_hash });
+        }
+        if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        if block.header.timestamp <= parent.header.timestamp {
+            return Err(BtcError::TimestampTooOld {
+                timestamp: block.header.timestamp,
+                prev_timestamp: parent.header.timestamp,
+            });
+        }
+        self.side_blocks.insert(block_hash, block);
+        branch.push(block_hash);
+
+        let branch_work = Self::chain_work(self.blocks[..=fork_index].iter())
+            + Self::chain_work(branch.iter().map(|hash| &self.side_blocks[hash]));
+        let result = if branch_work <= Self::chain_work(self.blocks.iter()) {
+            Ok(BlockOutcome::SideBranch)
         } else {
-            new_target
+            self.reorganize(fork_index, branch)
+                .map(|()| BlockOutcome::Reorganized {
+                    fork_height: fork_index,
+                })
         };
-        self.target = new_target.min(crate::MIN_TARGET);
+        self.prune_side_blocks();
+        result
     }
 
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        // all inputs must match known UTXOs, and must be unique
-        let mut known_inputs = HashSet::new();
-
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+    // forget the side blocks no reorganization can reach anymore, because
+    // they fork too deep below the tip or their parent is gone, then the
+    // lowest ones until at most max_side_blocks are left
+    fn prune_side_blocks(&mut self) {
+        // height of every side block, None if it doesn't lead back to
+        // the active chain
+        let mut heights: HashMap<Hash, Option<usize>> = HashMap::new();
+        for hash in self.side_blocks.keys() {
+            let mut path = vec![];
+            let mut cursor = *hash;
+            let mut height = loop {
+                if let Some(height) = heights.get(&cursor) {
+                    break *height;
+                }
+                if let Some(height) = self.height(&cursor) {
+                    break Some(height);
+                }
+                let Some(side_block) = self.side_blocks.get(&cursor) else {
+                    break None;
+                };
+                path.push(cursor);
+                cursor = side_block.header.prev_block_hash;
+            };
+            for hash in path.into_iter().rev() {
+                height = height.map(|height| height + 1);
+                heights.insert(hash, height);
             }
+        }
 
-            if known_inputs.contains(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+        let tip_height = self.blocks.len().saturating_sub(1);
+        let mut kept: Vec<(usize, Hash)> = vec![];
+        for (hash, height) in heights {
+            match height {
+                Some(height) if height + self.params.max_reorg_depth > tip_height => {
+                    kept.push((height, hash))
+                }
+                _ => {
+                    self.side_blocks.remove(&hash);
+                }
+            }
+        }
+        if kept.len() > self.params.max_side_blocks {
+            kept.sort_unstable_by_key(|(height, _)| *height);
+            for (_, hash) in &kept[..kept.len() - self.params.max_side_blocks] {
+                self.side_blocks.remove(hash);
             }
+        }
+    }
 
-            known_inputs.insert(input.prev_transaction_output_hash);
-        }
-
-        /*
-         * check if any of the UTXOs have the bool set to true and if so,
-         * find the transaction that references them in mempool,
-         * remove it, and set all the utxos it references to false
-         */
-        for input in &transaction.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
-                // find the transaction that references the UTXO we are trying to reference
-                let referencing_transaction =
-                    self.mempool
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, transaction))| {
-                            transaction
-                                .outputs
-                                .iter()
-                                .any(|output| output.hash() == input.prev_transaction_output_hash)
-                        });
-
-                // if there is any, unmark all its utxos
-                if let Some((idx, (_, referencing_transaction))) = referencing_transaction {
-                    for input in &referencing_transaction.inputs {
-                        // set all utxos in this tx to false
-                        self.utxos
-                            .entry(input.prev_transaction_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                    }
-                    // remove the transaction from mempool
-                    self.mempool.remove(idx);
-                } else {
-                    // if there is no matching tx, set this utxo to false
-                    self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
+    // replace the active chain above fork_index with the given side branch
+    fn reorganize(&mut self, fork_index: usize, branch: Vec<Hash>) -> Result<()> {
+        let mempool = self.mempool.take();
+        let old_blocks = self.disconnect_blocks(fork_index);
+
+        for hash in &branch {
+            let block = self
+                .side_blocks
+                .remove(hash)
+                .expect("BUG: branch block is missing");
+            if let Err(e) = self.connect_block(block) {
+                // neither the invalid block nor anything descending from
+                // it can ever be connected
+                self.remove_descendants(*hash);
+                for block in self.disconnect_blocks(fork_index) {
+                    self.side_blocks.insert(block.hash(), block);
+                }
+                for block in old_blocks {
+                    self.connect_block(block)
+                        .expect("BUG: previously connected block is invalid");
                 }
+                self.readmit_to_mempool(mempool.into_entries().map(|entry| entry.transaction));
+                return Err(e);
+            }
+        }
+
+        // transactions from the disconnected blocks go back to the mempool
+        let mut transactions = vec![];
+        for block in old_blocks {
+            transactions.extend(block.transactions.iter().skip(1).cloned());
+            self.side_blocks.insert(block.hash(), block);
+        }
+        transactions.extend(mempool.into_entries().map(|entry| entry.transaction));
+        self.readmit_to_mempool(transactions);
+        Ok(())
+    }
+
+    // drop every side block descending from the given block, on any branch
+    fn remove_descendants(&mut self, hash: Hash) {
+        let mut removed = HashSet::from([hash]);
+        loop {
+            let children: Vec<Hash> = self
+                .side_blocks
+                .iter()
+                .filter(|(_, block)| removed.contains(&block.header.prev_block_hash))
+                .map(|(hash, _)| *hash)
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            for child in children {
+                self.side_blocks.remove(&child);
+                removed.insert(child);
             }
         }
+    }
+
+    // add transactions back after the active chain changed, dropping
+    // the ones that are now confirmed or conflicting
+    fn readmit_to_mempool(&mut self, transactions: impl IntoIterator<Item = Transaction>) {
+        for transaction in transactions {
+            let _ = self.add_to_mempool(transaction);
+        }
+    }
+
+    // disconnect every block above fork_index, returning them in chain order
+    fn disconnect_blocks(&mut self, fork_index: usize) -> Vec<Block> {
+        let mut blocks = vec![];
+        while self.blocks.len() > fork_index + 1 {
+            blocks.extend(self.disconnect_block());
+        }
+        blocks.reverse();
+        self.recalculate_target();
+        blocks
+    }
+
+    fn chain_work<'a>(blocks: impl Iterator<Item = &'a Block>) -> U256 {
+        blocks.fold(U256::zero(), |work, block| work + block.header.work())
+    }
+
+    // replay difficulty adjustments over the whole active chain
+    pub fn recalculate_target(&mut self) {
+        let blocks = std::mem::take(&mut self.blocks);
+        self.target = self.params.min_target;
+        for block in blocks {
+            self.blocks.push(block);
+            self.try_adjust_target();
+        }
+    }
 
-        // all inputs must be lower than all outputs
-        let all_inputs = transaction
+    // rebuild UTXO set from the blockchain
+    pub fn rebuild_utxos(&mut self) -> Result<()> {
+        self.utxos.clear();
+        self.undo.clear();
+        for block in &self.blocks {
+            let undo = Self::connect_utxos(&mut self.utxos, block)?;
+            self.undo.push(undo);
+        }
+        if self.index.is_some() {
+            self.index = Some(ChainIndex::new(self.blocks.iter()));
+        }
+        Ok(())
+    }
+
+    pub fn try_adjust_target(&mut self) {
+        let blocks = &self.blocks;
+        self.target = self
+            .params
+            .next_target(self.target, blocks.len(), |height| {
+                blocks[height].header.timestamp
+            });
+    }
+
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        self.add_to_mempool_at(transaction, Utc::now())
+    }
+
+    // add a transaction that arrived at the given time
+    fn add_to_mempool_at(
+        &mut self,
+        transaction: Transaction,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let hash = transaction.hash();
+        if self.mempool.contains(&hash) {
+            return Err(BtcError::DuplicateTransaction { hash });
+        }
+
+        // inputs may spend confirmed outputs or outputs of pending transactions
+        let spent_outputs: HashMap<Hash, TransactionOutput> = transaction
             .inputs
             .iter()
-            .map(|input| {
+            .filter_map(|input| {
+                let output_hash = input.prev_transaction_output_hash;
                 self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("error getting input")
-                    .1
-                    .value
+                    .get(&output_hash)
+                    .or_else(|| self.mempool.output(&output_hash))
+                    .map(|output| (output_hash, output.clone()))
             })
-            .sum::<u64>();
+            .collect();
 
-        let all_outputs = transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum::<u64>();
+        // run the same checks a block runs on its transactions
+        let fee = transaction.verify(&spent_outputs)?;
+        for output in &transaction.outputs {
+            let output_hash = output.hash();
+            if self.utxos.contains_key(&output_hash) || self.mempool.output(&output_hash).is_some()
+            {
+                return Err(BtcError::DuplicateOutput { output_hash });
+            }
+        }
+        let entry = MempoolEntry::new(transaction, timestamp, fee);
 
-        if all_inputs < all_outputs {
-            return Err(BtcError::InvalidTransaction);
+        let required = self.mempool.min_fee(entry.size);
+        if entry.fee < required {
+            return Err(BtcError::FeeTooLow {
+                required,
+                actual: entry.fee,
+            });
+        }
+        if entry.size > self.mempool.max_size() {
+            return Err(BtcError::MempoolFull);
         }
 
-        // mark the utxos as used
-        for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
+        // a transaction spending outputs that pending transactions already
+        // spend has to pay enough to replace all of them
+        let conflicts = self.mempool.conflicts(&entry.transaction);
+        let mut removed = vec![];
+        if !conflicts.is_empty() {
+            self.check_replacement(&entry, &conflicts)?;
+            for hash in &conflicts {
+                removed.extend(self.mempool.remove(hash));
+            }
         }
 
-        self.mempool.push((Utc::now(), transaction));
+        self.mempool.insert(entry);
 
-        //sort by miner fee
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("error getting input")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
+        // make room by evicting the worst paying packages, which may
+        // include the new transaction itself. if it doesn't stay, neither
+        // the transactions it replaced nor the ones evicted for it go
+        let evicted = self.mempool.trim_to_size();
+        if !self.mempool.contains(&hash) {
+            removed.extend(
+                evicted
+                    .into_iter()
+                    .filter(|entry| entry.transaction.hash() != hash),
+            );
+            self.mempool.reinsert(removed);
+            return Err(BtcError::MempoolFull);
+        }
+        Ok(())
+    }
+
+    // limit the mempool to max_size bytes and set the fee rate in
+    // satoshis per byte it asks for while empty
+    pub fn set_mempool_limits(&mut self, max_size: usize, min_fee_rate: u64) {
+        self.mempool.set_limits(max_size, min_fee_rate);
+    }
 
-            let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+    // replace-by-fee rules: the replacement must pay a higher fee rate than
+    // every transaction it replaces, and its fee must exceed the combined
+    // fees of everything it evicts by at least MIN_REPLACEMENT_FEE_BUMP
+    fn check_replacement(&self, entry: &MempoolEntry, conflicts: &HashSet<Hash>) -> Result<()> {
+        let mut evicted = conflicts.clone();
+        for hash in conflicts {
+            let conflict = self
+                .mempool
+                .get(hash)
+                .expect("BUG: conflicting transaction is missing");
+            if entry.cmp_fee_rate(conflict) != Ordering::Greater {
+                return Err(BtcError::ReplacementFeeRateTooLow { replaced: *hash });
+            }
+            evicted.extend(self.mempool.descendants(hash));
+        }
 
-            let miner_fee = all_inputs - all_outputs;
+        // the replacement can't depend on a transaction it evicts
+        for input in &entry.transaction.inputs {
+            let creator = self.mempool.creator(&input.prev_transaction_output_hash);
+            if let Some(creator) = creator.filter(|creator| evicted.contains(creator)) {
+                return Err(BtcError::ReplacementSpendsReplaced { replaced: *creator });
+            }
+        }
 
-            miner_fee
-        });
+        let replaced_fee = Amount::checked_sum(evicted.iter().map(|hash| {
+            self.mempool
+                .get(hash)
+                .expect("BUG: evicted transaction is missing")
+                .fee
+        }))?;
+        let required =
+            replaced_fee.checked_add(Amount::from_sat(crate::MIN_REPLACEMENT_FEE_BUMP)?)?;
+        if entry.fee < required {
+            return Err(BtcError::InsufficientReplacementFee {
+                required,
+                actual: entry.fee,
+            });
+        }
         Ok(())
     }
 
-    //remove transactions older than max_mempool_age
-    pub fn cleanup_mempool(&mut self) {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+    // build a block on top of the tip for a miner to work on, filled with
+    // the best paying mempool transactions and a coinbase paying the reward
+    // and their fees to pubkey
+    pub fn build_template(&self, pubkey: PublicKey) -> Result<Block> {
+        let entries = self
+            .mempool
+            .select_for_block(self.params.block_transaction_cap);
+        let fees = Amount::checked_sum(entries.iter().map(|entry| entry.fee))?;
 
-        self.mempool.retain(|(timestamp, transaction)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
-            {
-                //push all utxos to unmark to the vec so we can unmark them later
-                utxo_hashes_to_unmark.extend(
-                    transaction
-                        .inputs
-                        .iter()
-                        .map(|input| input.prev_transaction_output_hash),
-                );
-                false
-            } else {
-                true
-            }
-        });
+        let mut transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                pubkey,
+                unique_id: Uuid::new_v4(),
+                value: self.calculate_block_reward().checked_add(fees)?,
+            }],
+        )];
+        transactions.extend(entries.into_iter().map(|entry| entry.transaction.clone()));
 
-        // unmark all of the utxos
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
-            });
+        let prev_block_hash = self
+            .blocks
+            .last()
+            .map(|last_block| last_block.hash())
+            .unwrap_or(Hash::zero());
+        let header = BlockHeader::new(
+            Utc::now(),
+            0,
+            prev_block_hash,
+            MerkleRoot::calculate(&transactions),
+            self.target,
+        );
+        Ok(Block::new(header, transactions))
+    }
+
+    // add back transactions saved by a previous run, keeping their arrival
+    // time. the ones that expired or no longer fit the chain are dropped.
+    // returns how many were restored
+    pub fn restore_mempool(&mut self, snapshot: MempoolSnapshot) -> usize {
+        let max_age = chrono::Duration::seconds(self.params.max_mempool_transaction_age as i64);
+        let now = Utc::now();
+        let mut restored = 0;
+        for (timestamp, transaction) in snapshot.transactions {
+            if now - timestamp > max_age {
+                continue;
+            }
+            if self.add_to_mempool_at(transaction, timestamp).is_ok() {
+                restored += 1;
+            }
         }
+        restored
+    }
+
+    //remove transactions older than max_mempool_age
+    pub fn cleanup_mempool(&mut self) {
+        self.mempool.remove_expired(
+            Utc::now(),
+            chrono::Duration::seconds(self.params.max_mempool_transaction_age as i64),
+        );
+    }
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new(ChainParams::default())
     }
 }
 
 impl Saveable for Blockchain {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize blockchain"))
+        let mut blockchain: Blockchain = ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize blockchain")
+        })?;
+        blockchain.heights = blockchain
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (block.hash(), height))
+            .collect();
+        Ok(blockchain)
     }
 
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -312,3 +777,425 @@ impl Saveable for Blockchain {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "failed to serialize blockchain"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::{
+        crypto::{PrivateKey, Signature},
+        types::{SigHashType, TransactionInput},
+    };
+
+    // a regtest chain holding only the genesis block
+    fn chain() -> Blockchain {
+        let params = ChainParams::regtest();
+        let mut blockchain = Blockchain::new(params.clone());
+        blockchain.add_block(params.genesis_block()).unwrap();
+        blockchain
+    }
+
+    fn output(value: u64, key: &PrivateKey) -> TransactionOutput {
+        TransactionOutput {
+            value: Amount::from_sat(value).unwrap(),
+            unique_id: Uuid::new_v4(),
+            pubkey: key.public_key(),
+        }
+    }
+
+    // a block at height on top of parent, its coinbase paying the reward
+    // and fees to key. any hash meets the regtest target, so there is
+    // nothing to mine
+    fn block(
+        parent: &Block,
+        height: u64,
+        key: &PrivateKey,
+        fees: u64,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let reward = ChainParams::regtest().block_reward(height).to_sat();
+        let mut all = vec![Transaction::new(vec![], vec![output(reward + fees, key)])];
+        all.extend(transactions);
+        let header = BlockHeader::new(
+            parent.header.timestamp + TimeDelta::seconds(1),
+            0,
+            parent.hash(),
+            MerkleRoot::calculate(&all),
+            parent.header.target,
+        );
+        Block::new(header, all)
+    }
+
+    // spend outputs locked to key, paying everything but fee back to it
+    fn spend(outputs: &[&TransactionOutput], key: &PrivateKey, fee: u64) -> Transaction {
+        let total: u64 = outputs.iter().map(|output| output.value.to_sat()).sum();
+        let new_outputs = vec![output(total - fee, key)];
+        let hashes: Vec<Hash> = outputs.iter().map(|output| output.hash()).collect();
+        let inputs = hashes
+            .iter()
+            .enumerate()
+            .map(|(input_index, hash)| {
+                let sighash = Transaction::signature_hash_for(
+                    &hashes,
+                    &new_outputs,
+                    input_index,
+                    SigHashType::All,
+                )
+                .unwrap();
+                TransactionInput {
+                    prev_transaction_output_hash: *hash,
+                    signature: Signature::sign_hash(&sighash, key),
+                    sighash_type: SigHashType::All,
+                }
+            })
+            .collect();
+        Transaction::new(inputs, new_outputs)
+    }
+
+    fn tip(blockchain: &Blockchain) -> Hash {
+        blockchain.blocks().last().unwrap().hash()
+    }
+
+    fn coinbase_output(block: &Block) -> &TransactionOutput {
+        &block.transactions[0].outputs[0]
+    }
+
+    // every entry's package totals match the ones added up from scratch
+    fn assert_package_totals(mempool: &Mempool) {
+        let total = |hashes: HashSet<Hash>| {
+            hashes.iter().fold((0, 0), |(fee, size), hash| {
+                let entry = mempool.get(hash).unwrap();
+                (fee + entry.fee.to_sat() as u128, size + entry.size as u128)
+            })
+        };
+        for entry in mempool.iter() {
+            let hash = entry.transaction.hash();
+            let mut ancestors = mempool.ancestors(&hash);
+            ancestors.insert(hash);
+            let mut descendants = mempool.descendants(&hash);
+            descendants.insert(hash);
+            assert_eq!(entry.ancestor_package(), total(ancestors));
+            assert_eq!(entry.descendant_package(), total(descendants));
+        }
+    }
+
+    #[test]
+    fn reorganizes_to_a_branch_with_more_work() {
+        let (alice, bob) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        let a2 = block(&a1, 2, &alice, 0, vec![]);
+        let b2 = block(&a1, 2, &bob, 0, vec![]);
+        let b3 = block(&b2, 3, &bob, 0, vec![]);
+
+        assert_eq!(
+            blockchain.add_block(a1.clone()),
+            Ok(BlockOutcome::Connected)
+        );
+        assert_eq!(
+            blockchain.add_block(a2.clone()),
+            Ok(BlockOutcome::Connected)
+        );
+        assert_eq!(
+            blockchain.add_block(b2.clone()),
+            Ok(BlockOutcome::SideBranch)
+        );
+        assert_eq!(
+            blockchain.add_block(b3.clone()),
+            Ok(BlockOutcome::Reorganized { fork_height: 1 })
+        );
+
+        assert_eq!(tip(&blockchain), b3.hash());
+        assert_eq!(blockchain.height(&b2.hash()), Some(2));
+        // the old tip is kept on a side branch
+        assert_eq!(blockchain.height(&a2.hash()), None);
+        assert!(blockchain.contains_block(&a2.hash()));
+        let utxos = blockchain.utxos();
+        assert!(!utxos.contains_key(&coinbase_output(&a2).hash()));
+        assert!(utxos.contains_key(&coinbase_output(&b2).hash()));
+        assert!(utxos.contains_key(&coinbase_output(&b3).hash()));
+    }
+
+    #[test]
+    fn failed_reorganization_restores_the_active_chain() {
+        let (alice, bob) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        let a2 = block(&a1, 2, &alice, 0, vec![]);
+        let b2 = block(&a1, 2, &bob, 0, vec![]);
+        // claims more than the reward, which only shows once connected
+        let b3 = block(&b2, 3, &bob, 1, vec![]);
+        let b4 = block(&b3, 4, &bob, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        blockchain.add_block(a2.clone()).unwrap();
+        blockchain.add_block(b2.clone()).unwrap();
+        let utxos = blockchain.utxos().clone();
+
+        let result = blockchain.add_block(b3.clone());
+        assert!(matches!(
+            result,
+            Err(BtcError::InvalidBlockTransaction { tx_index: 0, .. })
+        ));
+
+        assert_eq!(tip(&blockchain), a2.hash());
+        assert_eq!(blockchain.block_height(), 3);
+        assert_eq!(blockchain.utxos().len(), utxos.len());
+        assert!(utxos
+            .keys()
+            .all(|hash| blockchain.utxos().contains_key(hash)));
+        // the valid part of the branch may still win later, the invalid
+        // block is gone and nothing can be built on it
+        assert!(blockchain.contains_block(&b2.hash()));
+        assert!(!blockchain.contains_block(&b3.hash()));
+        assert!(matches!(
+            blockchain.add_block(b4),
+            Err(BtcError::UnknownParentBlock { .. })
+        ));
+    }
+
+    #[test]
+    fn replacement_must_pay_for_what_it_evicts() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        let coin = coinbase_output(&a1);
+
+        let original = spend(&[coin], &alice, 2_000);
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        // a higher fee rate, but not by MIN_REPLACEMENT_FEE_BUMP
+        let cheap = spend(&[coin], &alice, 2_500);
+        assert!(matches!(
+            blockchain.add_to_mempool(cheap.clone()),
+            Err(BtcError::InsufficientReplacementFee { .. })
+        ));
+        assert!(blockchain.mempool().contains(&original.hash()));
+        assert!(!blockchain.mempool().contains(&cheap.hash()));
+
+        let replacement = spend(&[coin], &alice, 2_000 + crate::MIN_REPLACEMENT_FEE_BUMP);
+        blockchain.add_to_mempool(replacement.clone()).unwrap();
+        assert!(!blockchain.mempool().contains(&original.hash()));
+        assert!(blockchain.mempool().contains(&replacement.hash()));
+        assert_eq!(blockchain.mempool().len(), 1);
+    }
+
+    #[test]
+    fn template_puts_parents_before_children() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        let a2 = block(&a1, 2, &alice, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        blockchain.add_block(a2.clone()).unwrap();
+
+        // the child pays for its cheap parent, so their package beats
+        // the unrelated transaction
+        let parent = spend(&[coinbase_output(&a1)], &alice, 1_000);
+        let child = spend(&[&parent.outputs[0]], &alice, 50_000);
+        let unrelated = spend(&[coinbase_output(&a2)], &alice, 5_000);
+        blockchain.add_to_mempool(parent.clone()).unwrap();
+        blockchain.add_to_mempool(child.clone()).unwrap();
+        blockchain.add_to_mempool(unrelated.clone()).unwrap();
+
+        let mut template = blockchain.build_template(alice.public_key()).unwrap();
+        let hashes: Vec<Hash> = template.transactions.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(hashes[1..], [parent.hash(), child.hash(), unrelated.hash()]);
+
+        template.header.timestamp = a2.header.timestamp + TimeDelta::seconds(1);
+        assert_eq!(blockchain.add_block(template), Ok(BlockOutcome::Connected));
+        assert!(blockchain.mempool().is_empty());
+    }
+
+    #[test]
+    fn block_may_spend_outputs_created_earlier_in_it() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+
+        let first = spend(&[coinbase_output(&a1)], &alice, 1_000);
+        let second = spend(&[&first.outputs[0]], &alice, 1_000);
+        let a2 = block(&a1, 2, &alice, 2_000, vec![first.clone(), second.clone()]);
+        assert_eq!(blockchain.add_block(a2), Ok(BlockOutcome::Connected));
+
+        let utxos = blockchain.utxos();
+        assert!(!utxos.contains_key(&coinbase_output(&a1).hash()));
+        // spent within the block, so it never reaches the utxo set
+        assert!(!utxos.contains_key(&first.outputs[0].hash()));
+        assert!(utxos.contains_key(&second.outputs[0].hash()));
+    }
+
+    #[test]
+    fn block_spending_an_output_twice_is_rejected() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+
+        let coin = coinbase_output(&a1);
+        let first = spend(&[coin], &alice, 1_000);
+        let second = spend(&[coin], &alice, 2_000);
+        let a2 = block(&a1, 2, &alice, 3_000, vec![first, second]);
+        assert_eq!(
+            blockchain.add_block(a2),
+            Err(BtcError::DoubleSpend {
+                input_index: 0,
+                output_hash: coin.hash(),
+            }
+            .in_transaction(2))
+        );
+        assert_eq!(tip(&blockchain), a1.hash());
+        assert!(blockchain.utxos().contains_key(&coin.hash()));
+    }
+
+    #[test]
+    fn block_recreating_an_unspent_output_is_rejected() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+
+        // the same coinbase output again would overwrite the unspent one
+        let mut a2 = block(&a1, 2, &alice, 0, vec![]);
+        a2.transactions[0] = a1.transactions[0].clone();
+        a2.header.merkle_root = MerkleRoot::calculate(&a2.transactions);
+        let output_hash = coinbase_output(&a1).hash();
+        assert_eq!(
+            blockchain.add_block(a2),
+            Err(BtcError::DuplicateOutput { output_hash }.in_transaction(0))
+        );
+        assert_eq!(tip(&blockchain), a1.hash());
+    }
+
+    #[test]
+    fn replacing_a_chain_keeps_package_totals_exact() {
+        // removal order used to depend on hash set iteration, so try a few
+        for _ in 0..20 {
+            let alice = PrivateKey::new_key();
+            let mut blockchain = chain();
+            let genesis = blockchain.blocks().next().unwrap().clone();
+            let a1 = block(&genesis, 1, &alice, 0, vec![]);
+            blockchain.add_block(a1.clone()).unwrap();
+
+            let root = spend(&[coinbase_output(&a1)], &alice, 1_000);
+            let child = spend(&[&root.outputs[0]], &alice, 1_000);
+            let grandchild = spend(&[&child.outputs[0]], &alice, 1_000);
+            let great_grandchild = spend(&[&grandchild.outputs[0]], &alice, 1_000);
+            for transaction in [&root, &child, &grandchild, &great_grandchild] {
+                blockchain.add_to_mempool(transaction.clone()).unwrap();
+            }
+            assert_package_totals(blockchain.mempool());
+
+            let replacement = spend(&[&root.outputs[0]], &alice, 10_000);
+            blockchain.add_to_mempool(replacement.clone()).unwrap();
+            assert_eq!(blockchain.mempool().len(), 2);
+            assert_package_totals(blockchain.mempool());
+            let root = blockchain.mempool().get(&root.hash()).unwrap();
+            assert_eq!(
+                root.descendant_package(),
+                (11_000, (root.size + replacement.size()) as u128)
+            );
+        }
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_package_with_the_lowest_fee_rate() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        let a2 = block(&a1, 2, &alice, 0, vec![]);
+        let a3 = block(&a2, 3, &alice, 0, vec![]);
+        for block in [&a1, &a2, &a3] {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        // the parent pays the least per byte on its own, but its child
+        // pays for it, so the unrelated transaction goes instead
+        let parent = spend(&[coinbase_output(&a1)], &alice, 2_000);
+        let child = spend(&[&parent.outputs[0]], &alice, 60_000);
+        let poor = spend(&[coinbase_output(&a2)], &alice, 20_000);
+        let newcomer = spend(&[coinbase_output(&a3)], &alice, 40_000);
+        let max_size = parent.size() + child.size() + poor.size() + 16;
+        blockchain.set_mempool_limits(max_size, crate::MIN_RELAY_FEE_RATE);
+        for transaction in [&parent, &child, &poor] {
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        blockchain.add_to_mempool(newcomer.clone()).unwrap();
+        let mempool = blockchain.mempool();
+        assert!(mempool.contains(&parent.hash()));
+        assert!(mempool.contains(&child.hash()));
+        assert!(!mempool.contains(&poor.hash()));
+        assert!(mempool.contains(&newcomer.hash()));
+        assert!(mempool.size() <= max_size);
+        assert_package_totals(mempool);
+    }
+
+    #[test]
+    fn transaction_below_the_minimum_fee_is_rejected() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        let coin = coinbase_output(&a1);
+
+        let cheap = spend(&[coin], &alice, 1);
+        let required = blockchain.mempool().min_fee(cheap.size());
+        assert_eq!(
+            blockchain.add_to_mempool(cheap.clone()),
+            Err(BtcError::FeeTooLow {
+                required,
+                actual: Amount::from_sat(1).unwrap(),
+            })
+        );
+        assert!(blockchain.mempool().is_empty());
+
+        // signatures differ in size by a few bytes, leave room for that
+        let enough = spend(&[coin], &alice, required.to_sat() + 16);
+        blockchain.add_to_mempool(enough.clone()).unwrap();
+        assert!(blockchain.mempool().contains(&enough.hash()));
+    }
+
+    #[test]
+    fn evicted_replacement_keeps_what_it_would_replace() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = chain();
+        let genesis = blockchain.blocks().next().unwrap().clone();
+        let a1 = block(&genesis, 1, &alice, 0, vec![]);
+        let a2 = block(&a1, 2, &alice, 0, vec![]);
+        let a3 = block(&a2, 3, &alice, 0, vec![]);
+        for block in [&a1, &a2, &a3] {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        let original = spend(&[coinbase_output(&a1)], &alice, 5_000);
+        let rich = spend(&[coinbase_output(&a2)], &alice, 100_000);
+        blockchain.set_mempool_limits(original.size() + rich.size(), crate::MIN_RELAY_FEE_RATE);
+        blockchain.add_to_mempool(original.clone()).unwrap();
+        blockchain.add_to_mempool(rich.clone()).unwrap();
+
+        // pays enough to replace the original, but its extra input makes
+        // it too big to fit next to the rich transaction
+        let replacement = spend(&[coinbase_output(&a1), coinbase_output(&a3)], &alice, 8_000);
+        assert_eq!(
+            blockchain.add_to_mempool(replacement.clone()),
+            Err(BtcError::MempoolFull)
+        );
+        let mempool = blockchain.mempool();
+        assert_eq!(mempool.len(), 2);
+        assert!(mempool.contains(&original.hash()));
+        assert!(mempool.contains(&rich.hash()));
+        assert_package_totals(mempool);
+    }
+}
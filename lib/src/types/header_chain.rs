@@ -0,0 +1,159 @@
+use crate::{
+    error::{BtcError, Result},
+    params::ChainParams,
+    sha256::Hash,
+    U256,
+};
+
+use super::{block::BlockHeader, blockchain::Blockchain};
+
+// the most recent blocks a locator lists one by one, after that the
+// steps between listed blocks double
+const LOCATOR_DENSE_BLOCKS: usize = 10;
+
+// a chain of headers validated without their transactions. a syncing node
+// builds one from a peer's headers to learn which blocks to download
+#[derive(Clone, Debug)]
+pub struct HeaderChain {
+    params: ChainParams,
+    headers: Vec<BlockHeader>,
+    // hash of every header, by height
+    hashes: Vec<Hash>,
+}
+
+impl HeaderChain {
+    // the headers of the blockchain's active chain, or just the genesis
+    // block if it has none
+    pub fn new(blockchain: &Blockchain) -> Self {
+        let params = blockchain.params().clone();
+        let mut headers: Vec<BlockHeader> = blockchain
+            .blocks()
+            .map(|block| block.header.clone())
+            .collect();
+        if headers.is_empty() {
+            headers.push(params.genesis_block().header);
+        }
+        let hashes = headers.iter().map(|header| header.hash()).collect();
+        HeaderChain {
+            params,
+            headers,
+            hashes,
+        }
+    }
+
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    pub fn block_height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    // expected number of hashes needed to produce every header
+    pub fn work(&self) -> U256 {
+        self.headers
+            .iter()
+            .fold(U256::zero(), |work, header| work + header.work())
+    }
+
+    // target the next header must have
+    pub fn next_target(&self) -> U256 {
+        let headers = &self.headers;
+        let tip = headers.last().expect("BUG: header chain is empty");
+        self.params
+            .next_target(tip.target, headers.len(), |height| {
+                headers[height].timestamp
+            })
+    }
+
+    // hashes describing this chain to a peer: the most recent blocks one by
+    // one, then exponentially sparser back to genesis. the peer answers with
+    // the headers after the first of them on its active chain
+    pub fn locator(&self) -> Vec<Hash> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut height = self.hashes.len() - 1;
+        loop {
+            locator.push(self.hashes[height]);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= LOCATOR_DENSE_BLOCKS {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    // validate headers that follow one already in the chain and append them.
+    // any headers after the one they follow are replaced, so this chain
+    // becomes the peer's. if a header is invalid the chain is left unchanged
+    pub fn extend(&mut self, headers: Vec<BlockHeader>) -> Result<()> {
+        let Some(first) = headers.first() else {
+            return Ok(());
+        };
+        let prev_block_hash = first.prev_block_hash;
+        let Some(fork_height) = self
+            .hashes
+            .iter()
+            .rposition(|hash| *hash == prev_block_hash)
+        else {
+            return Err(BtcError::UnknownParentBlock { prev_block_hash });
+        };
+
+        let replaced_headers = self.headers.split_off(fork_height + 1);
+        let replaced_hashes = self.hashes.split_off(fork_height + 1);
+        for header in headers {
+            if let Err(e) = self.push(header) {
+                self.headers.truncate(fork_height + 1);
+                self.hashes.truncate(fork_height + 1);
+                self.headers.extend(replaced_headers);
+                self.hashes.extend(replaced_hashes);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // check a header against the tip with the same rules as a block, except
+    // for its transactions
+    fn push(&mut self, header: BlockHeader) -> Result<()> {
+        let tip_hash = *self.hashes.last().expect("BUG: header chain is empty");
+        if header.prev_block_hash != tip_hash {
+            return Err(BtcError::PrevBlockHashMismatch {
+                expected: tip_hash,
+                actual: header.prev_block_hash,
+            });
+        }
+
+        let expected_target = self.next_target();
+        if header.target != expected_target {
+            return Err(BtcError::UnexpectedTarget {
+                expected: expected_target,
+                actual: header.target,
+            });
+        }
+
+        let hash = header.hash();
+        if !hash.matches_target(header.target) {
+            return Err(BtcError::TargetNotMet { hash });
+        }
+
+        let prev_timestamp = self.headers.last().unwrap().timestamp;
+        if header.timestamp <= prev_timestamp {
+            return Err(BtcError::TimestampTooOld {
+                timestamp: header.timestamp,
+                prev_timestamp,
+            });
+        }
+
+        self.headers.push(header);
+        self.hashes.push(hash);
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto::PublicKey, sha256::Hash};
+
+use super::{block::Block, transaction::TransactionOutput};
+
+// where a transaction sits in the active chain
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    pub height: usize,
+    // index of the transaction within its block
+    pub position: usize,
+}
+
+// an output created on the active chain and the transaction spending it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutputRecord {
+    pub hash: Hash,
+    pub output: TransactionOutput,
+    pub created: TxLocation,
+    pub spent: Option<TxLocation>,
+}
+
+// lookups over the active chain that would otherwise scan every block:
+// transactions by hash and the outputs locked to each key. kept in step
+// with the chain as blocks are connected and disconnected
+#[derive(Clone, Debug, Default)]
+pub struct ChainIndex {
+    transactions: HashMap<Hash, TxLocation>,
+    outputs: HashMap<Hash, OutputRecord>,
+    // output hashes by the key they are locked to, in chain order
+    by_key: HashMap<PublicKey, Vec<Hash>>,
+}
+
+impl ChainIndex {
+    pub fn new<'a>(blocks: impl Iterator<Item = &'a Block>) -> Self {
+        let mut index = ChainIndex::default();
+        for (height, block) in blocks.enumerate() {
+            index.connect_block(height, block);
+        }
+        index
+    }
+
+    pub fn transaction(&self, hash: &Hash) -> Option<TxLocation> {
+        self.transactions.get(hash).copied()
+    }
+
+    pub fn output(&self, hash: &Hash) -> Option<&OutputRecord> {
+        self.outputs.get(hash)
+    }
+
+    // every output ever locked to the key, spent or not, oldest first
+    pub fn outputs_of(&self, key: &PublicKey) -> impl Iterator<Item = &OutputRecord> {
+        self.by_key
+            .get(key)
+            .into_iter()
+            .flatten()
+            .map(|hash| &self.outputs[hash])
+    }
+
+    // the outputs locked to the key that no block spends
+    pub fn unspent_of(&self, key: &PublicKey) -> impl Iterator<Item = &OutputRecord> {
+        self.outputs_of(key).filter(|record| record.spent.is_none())
+    }
+
+    // index a block appended to the active chain at the given height
+    pub(crate) fn connect_block(&mut self, height: usize, block: &Block) {
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            let location = TxLocation { height, position };
+            self.transactions.insert(transaction.hash(), location);
+            for input in &transaction.inputs {
+                if let Some(record) = self.outputs.get_mut(&input.prev_transaction_output_hash) {
+                    record.spent = Some(location);
+                }
+            }
+            for output in &transaction.outputs {
+                let hash = output.hash();
+                self.by_key
+                    .entry(output.pubkey.clone())
+                    .or_default()
+                    .push(hash);
+                self.outputs.insert(
+                    hash,
+                    OutputRecord {
+                        hash,
+                        output: output.clone(),
+                        created: location,
+                        spent: None,
+                    },
+                );
+            }
+        }
+    }
+
+    // forget the tip of the active chain, undoing connect_block
+    pub(crate) fn disconnect_block(&mut self, block: &Block) {
+        for transaction in block.transactions.iter().rev() {
+            for output in &transaction.outputs {
+                let hash = output.hash();
+                self.outputs.remove(&hash);
+                if let Some(hashes) = self.by_key.get_mut(&output.pubkey) {
+                    hashes.retain(|h| *h != hash);
+                    if hashes.is_empty() {
+                        self.by_key.remove(&output.pubkey);
+                    }
+                }
+            }
+            for input in &transaction.inputs {
+                if let Some(record) = self.outputs.get_mut(&input.prev_transaction_output_hash) {
+                    record.spent = None;
+                }
+            }
+            self.transactions.remove(&transaction.hash());
+        }
+    }
+}
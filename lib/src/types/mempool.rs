@@ -0,0 +1,543 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{sha256::Hash, util::Saveable};
+
+use super::{
+    amount::Amount,
+    block::Block,
+    transaction::{Transaction, TransactionOutput},
+};
+
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    pub timestamp: DateTime<Utc>,
+    pub fee: Amount,
+    // serialized size in bytes
+    pub size: usize,
+    // mempool transactions this one spends outputs of
+    parents: HashSet<Hash>,
+    // mempool transactions spending outputs of this one
+    children: HashSet<Hash>,
+    // fee in satoshis, size and count of this transaction together with
+    // all its mempool ancestors, kept up to date as entries come and go
+    ancestor_fee: u64,
+    ancestor_size: usize,
+    ancestor_count: usize,
+    // the same for this transaction together with all its descendants
+    descendant_fee: u64,
+    descendant_size: usize,
+}
+
+impl MempoolEntry {
+    pub fn new(transaction: Transaction, timestamp: DateTime<Utc>, fee: Amount) -> Self {
+        let size = transaction.size();
+        MempoolEntry {
+            size,
+            transaction,
+            timestamp,
+            fee,
+            parents: HashSet::new(),
+            children: HashSet::new(),
+            ancestor_fee: fee.to_sat(),
+            ancestor_size: size,
+            ancestor_count: 1,
+            descendant_fee: fee.to_sat(),
+            descendant_size: size,
+        }
+    }
+
+    // compare fee per byte without going through floats
+    pub fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        cmp_fee_rate(
+            (self.fee.to_sat() as u128, self.size as u128),
+            (other.fee.to_sat() as u128, other.size as u128),
+        )
+    }
+
+    pub fn parents(&self) -> &HashSet<Hash> {
+        &self.parents
+    }
+
+    pub fn children(&self) -> &HashSet<Hash> {
+        &self.children
+    }
+
+    // this transaction and its mempool ancestors, the package a miner
+    // has to include to include it
+    pub(crate) fn ancestor_package(&self) -> (u128, u128) {
+        (self.ancestor_fee as u128, self.ancestor_size as u128)
+    }
+
+    // this transaction and its mempool descendants, the package
+    // evicted along with it
+    pub(crate) fn descendant_package(&self) -> (u128, u128) {
+        (self.descendant_fee as u128, self.descendant_size as u128)
+    }
+}
+
+// compare two (fee, size) pairs by fee per byte
+fn cmp_fee_rate((fee, size): (u128, u128), (other_fee, other_size): (u128, u128)) -> Ordering {
+    (fee * other_size).cmp(&(other_fee * size))
+}
+
+// unconfirmed transactions, kept apart from the confirmed utxo set
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    entries: HashMap<Hash, MempoolEntry>,
+    // output hash -> hash of the mempool transaction spending it
+    spends: HashMap<Hash, Hash>,
+    // output hash -> hash of the mempool transaction creating it
+    outputs: HashMap<Hash, Hash>,
+    // serialized size of all entries in bytes
+    size: usize,
+    max_size: usize,
+    // satoshis per byte an empty mempool asks for
+    min_fee_rate: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::with_limits(crate::MAX_MEMPOOL_SIZE, crate::MIN_RELAY_FEE_RATE)
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(max_size: usize, min_fee_rate: u64) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            outputs: HashMap::new(),
+            size: 0,
+            max_size,
+            min_fee_rate,
+        }
+    }
+
+    // change the limits, evicting transactions if the pool no longer fits
+    pub(crate) fn set_limits(&mut self, max_size: usize, min_fee_rate: u64) {
+        self.max_size = max_size;
+        self.min_fee_rate = min_fee_rate;
+        self.trim_to_size();
+    }
+
+    // serialized size of all transactions in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    // fee a transaction of the given size must pay to get in. the fee rate
+    // rises linearly with usage, up to MEMPOOL_FULL_FEE_MULTIPLIER times
+    // min_fee_rate when the pool is full
+    pub fn min_fee(&self, size: usize) -> Amount {
+        let max_size = self.max_size.max(1) as u128;
+        let usage = self.size.min(self.max_size) as u128;
+        let multiplier = crate::MEMPOOL_FULL_FEE_MULTIPLIER as u128;
+        let scaled = max_size + (multiplier - 1) * usage;
+        let fee = (self.min_fee_rate as u128 * size as u128 * scaled).div_ceil(max_size);
+        u64::try_from(fee)
+            .ok()
+            .and_then(|fee| Amount::from_sat(fee).ok())
+            .unwrap_or(Amount::MAX)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&MempoolEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    // the mempool transaction spending an output, if any
+    pub fn spender(&self, output_hash: &Hash) -> Option<&Hash> {
+        self.spends.get(output_hash)
+    }
+
+    // the mempool transaction creating an output, if any
+    pub fn creator(&self, output_hash: &Hash) -> Option<&Hash> {
+        self.outputs.get(output_hash)
+    }
+
+    // an output created by a mempool transaction
+    pub fn output(&self, output_hash: &Hash) -> Option<&TransactionOutput> {
+        let creator = self.creator(output_hash)?;
+        self.entries[creator]
+            .transaction
+            .outputs
+            .iter()
+            .find(|output| output.hash() == *output_hash)
+    }
+
+    // every output created by a mempool transaction, with its hash
+    pub fn outputs(&self) -> impl Iterator<Item = (Hash, &TransactionOutput)> {
+        self.entries
+            .values()
+            .flat_map(|entry| entry.transaction.outputs.iter())
+            .map(|output| (output.hash(), output))
+    }
+
+    // mempool transactions spending any of the transaction's inputs
+    pub fn conflicts(&self, transaction: &Transaction) -> HashSet<Hash> {
+        transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spender(&input.prev_transaction_output_hash))
+            .copied()
+            .collect()
+    }
+
+    // every mempool transaction the given one depends on, directly or not
+    pub fn ancestors(&self, hash: &Hash) -> HashSet<Hash> {
+        self.walk(hash, MempoolEntry::parents)
+    }
+
+    // every mempool transaction depending on the given one, directly or not
+    pub fn descendants(&self, hash: &Hash) -> HashSet<Hash> {
+        self.walk(hash, MempoolEntry::children)
+    }
+
+    fn walk(&self, hash: &Hash, next: fn(&MempoolEntry) -> &HashSet<Hash>) -> HashSet<Hash> {
+        let mut found = HashSet::new();
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            let Some(entry) = self.entries.get(&hash) else {
+                continue;
+            };
+            for hash in next(entry) {
+                if found.insert(*hash) {
+                    stack.push(*hash);
+                }
+            }
+        }
+        found
+    }
+
+    // pick at most max_count transactions for a block, parents before their
+    // children. candidates are ranked by the fee rate of the package formed
+    // with their unselected ancestors, so a child can pay for its parent
+    pub fn select_for_block(&self, max_count: usize) -> Vec<&MempoolEntry> {
+        let mut selected: Vec<&MempoolEntry> = vec![];
+        let mut included: HashSet<Hash> = HashSet::new();
+        // fee, size and count of each unselected transaction's package,
+        // its ancestor totals less the ancestors selected so far
+        let mut packages: HashMap<Hash, (u128, u128, usize)> = self
+            .entries
+            .iter()
+            .map(|(hash, entry)| {
+                let (fee, size) = entry.ancestor_package();
+                (*hash, (fee, size, entry.ancestor_count))
+            })
+            .collect();
+
+        loop {
+            let best = packages
+                .iter()
+                .filter(|(_, (_, _, count))| selected.len() + count <= max_count)
+                .max_by(|(_, a), (_, b)| cmp_fee_rate((a.0, a.1), (b.0, b.1)))
+                .map(|(hash, _)| *hash);
+            let Some(best) = best else {
+                break;
+            };
+
+            let mut package: Vec<Hash> = self
+                .ancestors(&best)
+                .into_iter()
+                .filter(|ancestor| !included.contains(ancestor))
+                .collect();
+            package.push(best);
+            // an ancestor always has fewer ancestors than its descendants
+            package.sort_by_key(|hash| self.entries[hash].ancestor_count);
+            for hash in package {
+                let entry = &self.entries[&hash];
+                packages.remove(&hash);
+                for descendant in self.descendants(&hash) {
+                    if let Some((fee, size, count)) = packages.get_mut(&descendant) {
+                        *fee -= entry.fee.to_sat() as u128;
+                        *size -= entry.size as u128;
+                        *count -= 1;
+                    }
+                }
+                included.insert(hash);
+                selected.push(entry);
+            }
+        }
+
+        selected
+    }
+
+    // the caller must have removed any conflicts first and made sure
+    // every input is either confirmed or created by a mempool transaction
+    pub(crate) fn insert(&mut self, mut entry: MempoolEntry) {
+        let hash = entry.transaction.hash();
+        for input in &entry.transaction.inputs {
+            let output_hash = input.prev_transaction_output_hash;
+            self.spends.insert(output_hash, hash);
+            if let Some(parent) = self.outputs.get(&output_hash) {
+                entry.parents.insert(*parent);
+            }
+        }
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.insert(hash);
+            }
+        }
+        for output in &entry.transaction.outputs {
+            self.outputs.insert(output.hash(), hash);
+        }
+        let (fee, size) = (entry.fee.to_sat(), entry.size);
+        self.size += size;
+        self.entries.insert(hash, entry);
+
+        // a new entry has no descendants yet, only its ancestors change
+        let ancestors = self.ancestors(&hash);
+        let (mut ancestor_fee, mut ancestor_size) = (fee, size);
+        for ancestor in &ancestors {
+            let ancestor = self
+                .entries
+                .get_mut(ancestor)
+                .expect("BUG: ancestor is missing");
+            ancestor.descendant_fee += fee;
+            ancestor.descendant_size += size;
+            ancestor_fee += ancestor.fee.to_sat();
+            ancestor_size += ancestor.size;
+        }
+        let entry = self.entries.get_mut(&hash).expect("BUG: entry is missing");
+        entry.ancestor_fee = ancestor_fee;
+        entry.ancestor_size = ancestor_size;
+        entry.ancestor_count = ancestors.len() + 1;
+    }
+
+    // remove a transaction along with everything spending its outputs
+    pub(crate) fn remove(&mut self, hash: &Hash) -> Vec<MempoolEntry> {
+        if !self.entries.contains_key(hash) {
+            return vec![];
+        }
+        // deepest first, so every entry still reaches all its ancestors
+        // when it is taken off their descendant totals
+        let mut package: Vec<Hash> = self.descendants(hash).into_iter().collect();
+        package.sort_by_key(|hash| std::cmp::Reverse(self.entries[hash].ancestor_count));
+        package.push(*hash);
+        package
+            .iter()
+            .filter_map(|hash| self.remove_entry(hash))
+            .collect()
+    }
+
+    // remove a single transaction, leaving its children in place
+    fn remove_entry(&mut self, hash: &Hash) -> Option<MempoolEntry> {
+        let (fee, size) = {
+            let entry = self.entries.get(hash)?;
+            (entry.fee.to_sat(), entry.size)
+        };
+        for ancestor in self.ancestors(hash) {
+            if let Some(ancestor) = self.entries.get_mut(&ancestor) {
+                ancestor.descendant_fee -= fee;
+                ancestor.descendant_size -= size;
+            }
+        }
+        for descendant in self.descendants(hash) {
+            if let Some(descendant) = self.entries.get_mut(&descendant) {
+                descendant.ancestor_fee -= fee;
+                descendant.ancestor_size -= size;
+                descendant.ancestor_count -= 1;
+            }
+        }
+
+        let entry = self.entries.remove(hash)?;
+        self.size -= entry.size;
+        for input in &entry.transaction.inputs {
+            self.spends.remove(&input.prev_transaction_output_hash);
+        }
+        for output in &entry.transaction.outputs {
+            self.outputs.remove(&output.hash());
+        }
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(hash);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(hash);
+            }
+        }
+        Some(entry)
+    }
+
+    // drop transactions confirmed by the block and the ones it conflicts with.
+    // children of confirmed transactions stay, their inputs are confirmed now
+    pub(crate) fn remove_for_block(&mut self, block: &Block) {
+        for transaction in &block.transactions {
+            self.remove_entry(&transaction.hash());
+            for input in &transaction.inputs {
+                if let Some(hash) = self.spender(&input.prev_transaction_output_hash).copied() {
+                    self.remove(&hash);
+                }
+            }
+        }
+    }
+
+    // drop transactions that have been waiting longer than max_age,
+    // along with their descendants
+    pub(crate) fn remove_expired(&mut self, now: DateTime<Utc>, max_age: chrono::Duration) {
+        let expired: Vec<Hash> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now - entry.timestamp > max_age)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    // evict the packages with the lowest fee rate until the pool fits in
+    // max_size. a package is a transaction with all its descendants, which
+    // can't stay without it
+    pub(crate) fn trim_to_size(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = vec![];
+        while self.size > self.max_size {
+            let lowest = self
+                .entries
+                .iter()
+                .min_by(|(_, a), (_, b)| {
+                    cmp_fee_rate(a.descendant_package(), b.descendant_package())
+                })
+                .map(|(hash, _)| *hash);
+            let Some(hash) = lowest else {
+                break;
+            };
+            evicted.extend(self.remove(&hash));
+        }
+        evicted
+    }
+
+    // put back entries taken out by remove or trim_to_size, parents before
+    // their children. links and package totals are rebuilt from scratch
+    pub(crate) fn reinsert(&mut self, entries: Vec<MempoolEntry>) {
+        let mut pending: HashMap<Hash, MempoolEntry> = entries
+            .into_iter()
+            .map(|entry| (entry.transaction.hash(), entry))
+            .collect();
+        let created: HashMap<Hash, Hash> = pending
+            .iter()
+            .flat_map(|(hash, entry)| {
+                entry
+                    .transaction
+                    .outputs
+                    .iter()
+                    .map(move |output| (output.hash(), *hash))
+            })
+            .collect();
+        while !pending.is_empty() {
+            let ready: Vec<Hash> = pending
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.transaction.inputs.iter().all(|input| {
+                        created
+                            .get(&input.prev_transaction_output_hash)
+                            .is_none_or(|creator| !pending.contains_key(creator))
+                    })
+                })
+                .map(|(hash, _)| *hash)
+                .collect();
+            assert!(
+                !ready.is_empty(),
+                "BUG: mempool entries depend on each other"
+            );
+            for hash in ready {
+                let entry = pending.remove(&hash).expect("BUG: entry is missing");
+                self.insert(MempoolEntry::new(
+                    entry.transaction,
+                    entry.timestamp,
+                    entry.fee,
+                ));
+            }
+        }
+    }
+
+    // move every entry out, leaving an empty pool with the same limits
+    pub(crate) fn take(&mut self) -> Mempool {
+        let empty = Mempool::with_limits(self.max_size, self.min_fee_rate);
+        std::mem::replace(self, empty)
+    }
+
+    // hashes of all entries, parents before their children
+    fn topological_order(&self) -> Vec<Hash> {
+        let mut depths: Vec<(usize, Hash)> = self
+            .entries
+            .iter()
+            .map(|(hash, entry)| (entry.ancestor_count, *hash))
+            .collect();
+        // an ancestor always has fewer ancestors than its descendants
+        depths.sort_by_key(|(depth, _)| *depth);
+        depths.into_iter().map(|(_, hash)| hash).collect()
+    }
+
+    // all entries, parents before their children
+    pub(crate) fn into_entries(mut self) -> impl Iterator<Item = MempoolEntry> {
+        self.topological_order()
+            .into_iter()
+            .filter_map(move |hash| self.entries.remove(&hash))
+    }
+
+    // the pending transactions with the time they arrived, for saving to disk
+    pub fn snapshot(&self) -> MempoolSnapshot {
+        let transactions = self
+            .topological_order()
+            .iter()
+            .map(|hash| {
+                let entry = &self.entries[hash];
+                (entry.timestamp, entry.transaction.clone())
+            })
+            .collect();
+        MempoolSnapshot { transactions }
+    }
+}
+
+// pending transactions as written to disk, parents before their children.
+// they are only trusted after Blockchain::restore_mempool validates them again
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MempoolSnapshot {
+    pub transactions: Vec<(DateTime<Utc>, Transaction)>,
+}
+
+impl Saveable for MempoolSnapshot {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize mempool"))
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize mempool"))
+    }
+}
@@ -1,8 +1,12 @@
 use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
+use crate::types::Amount;
 use crate::util::Saveable;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::slice;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -19,6 +23,164 @@ impl Transaction {
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+
+    // the digest signed by the input at input_index. It is computed from
+    // the hashes of the outputs being spent rather than from the inputs,
+    // so it can be built before any input has been signed
+    pub fn signature_hash_for(
+        prev_output_hashes: &[Hash],
+        outputs: &[TransactionOutput],
+        input_index: usize,
+        sighash_type: SigHashType,
+    ) -> Result<Hash> {
+        let input_hash = prev_output_hashes
+            .get(input_index)
+            .ok_or(BtcError::InvalidTransacitonInput)?;
+
+        let inputs = if sighash_type.anyone_can_pay() {
+            slice::from_ref(input_hash)
+        } else {
+            prev_output_hashes
+        };
+
+        let outputs = if sighash_type.single() {
+            // there must be an output with the same index as the input
+            let output = outputs
+                .get(input_index)
+                .ok_or(BtcError::InvalidTransactionOutput)?;
+            slice::from_ref(output)
+        } else {
+            outputs
+        };
+
+        Ok(Hash::hash(&SigHashPreimage {
+            sighash_type,
+            // other inputs may be added around an anyone-can-pay input
+            input_index: (!sighash_type.anyone_can_pay()).then_some(input_index),
+            inputs,
+            outputs,
+        }))
+    }
+
+    pub fn signature_hash(&self, input_index: usize) -> Result<Hash> {
+        let sighash_type = self
+            .inputs
+            .get(input_index)
+            .ok_or(BtcError::InvalidTransacitonInput)?
+            .sighash_type;
+        let prev_output_hashes: Vec<Hash> = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_transaction_output_hash)
+            .collect();
+        Self::signature_hash_for(
+            &prev_output_hashes,
+            &self.outputs,
+            input_index,
+            sighash_type,
+        )
+    }
+
+    // check a non-coinbase transaction against the outputs it spends: every
+    // input must spend a distinct known output with a valid signature and
+    // the inputs must cover the outputs. Returns the fee paid to the miner
+    pub fn verify(&self, utxos: &HashMap<Hash, TransactionOutput>) -> Result<Amount> {
+        if self.inputs.is_empty() {
+            return Err(BtcError::NoInputs);
+        }
+
+        let mut spent_outputs = HashSet::new();
+        let mut input_value = Amount::ZERO;
+
+        for (input_index, input) in self.inputs.iter().enumerate() {
+            let output_hash = input.prev_transaction_output_hash;
+            let Some(prev_output) = utxos.get(&output_hash) else {
+                return Err(BtcError::UnknownOutput {
+                    input_index,
+                    output_hash,
+                });
+            };
+
+            if !spent_outputs.insert(output_hash) {
+                return Err(BtcError::DoubleSpend {
+                    input_index,
+                    output_hash,
+                });
+            }
+
+            // the signature must commit to this transaction
+            if !self.verify_input(input_index, &prev_output.pubkey) {
+                return Err(BtcError::InvalidInputSignature { input_index });
+            }
+
+            input_value = input_value.checked_add(prev_output.value)?;
+        }
+
+        let output_value = self.output_value()?;
+        if input_value < output_value {
+            return Err(BtcError::OutputsExceedInputs {
+                input_value,
+                output_value,
+            });
+        }
+
+        input_value.checked_sub(output_value)
+    }
+
+    // size of the serialized transaction in bytes
+    pub fn size(&self) -> usize {
+        let mut bytes = vec![];
+        ciborium::into_writer(self, &mut bytes).expect("BUG: failed to serialize transaction");
+        bytes.len()
+    }
+
+    pub fn output_value(&self) -> Result<Amount> {
+        Amount::checked_sum(self.outputs.iter().map(|output| output.value))
+    }
+
+    // check the signature of the input at input_index against the public key
+    // of the output it spends
+    pub fn verify_input(&self, input_index: usize, public_key: &PublicKey) -> bool {
+        match self.signature_hash(input_index) {
+            Ok(hash) => self.inputs[input_index].signature.verify(&hash, public_key),
+            Err(_) => false,
+        }
+    }
+}
+
+// everything an input's signature commits to
+#[derive(Serialize)]
+struct SigHashPreimage<'a> {
+    sighash_type: SigHashType,
+    input_index: Option<usize>,
+    inputs: &'a [Hash],
+    outputs: &'a [TransactionOutput],
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SigHashType {
+    /// sign all inputs and all outputs
+    #[default]
+    All,
+    /// sign all inputs and only the output with the same index as the input
+    Single,
+    /// sign only this input and all outputs
+    AllAnyoneCanPay,
+    /// sign only this input and the output with the same index as the input
+    SingleAnyoneCanPay,
+}
+
+impl SigHashType {
+    pub fn anyone_can_pay(self) -> bool {
+        matches!(
+            self,
+            SigHashType::AllAnyoneCanPay | SigHashType::SingleAnyoneCanPay
+        )
+    }
+
+    pub fn single(self) -> bool {
+        matches!(self, SigHashType::Single | SigHashType::SingleAnyoneCanPay)
+    }
 }
 
 impl Saveable for Transaction {
@@ -37,11 +199,14 @@ impl Saveable for Transaction {
 pub struct TransactionInput {
     pub prev_transaction_output_hash: Hash,
     pub signature: Signature,
+    // which parts of the transaction the signature commits to
+    #[serde(default)]
+    pub sighash_type: SigHashType,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
-    pub value: u64,
+    pub value: Amount,
     pub unique_id: Uuid,
     pub pubkey: PublicKey,
 }
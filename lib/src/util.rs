@@ -1,5 +1,5 @@
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{Read, Result as IoResult, Write},
     path::Path,
 };
@@ -30,7 +30,8 @@ impl MerkleRoot {
             }
             layer = new_layer;
         }
-        MerkleRoot(layer[0])
+        // a block without transactions is invalid, but must not crash us
+        MerkleRoot(layer.first().copied().unwrap_or(Hash::zero()))
     }
 }
 
@@ -40,9 +41,17 @@ where
 {
     fn load<I: Read>(reader: I) -> IoResult<Self>;
     fn save<O: Write>(&self, writer: O) -> IoResult<()>;
+    // write to a temporary file next to the destination and rename it
+    // into place, so a crash mid-write leaves the previous file intact
     fn save_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        let file = File::create(&path)?;
-        self.save(file)
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let file = File::create(&tmp_path)?;
+        self.save(&file)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
     }
     fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
         let file = File::open(&path)?;
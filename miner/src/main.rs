@@ -1,6 +1,4 @@
 use std::{
-    env,
-    process::exit,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -10,46 +8,74 @@ use std::{
 };
 
 use anyhow::{anyhow, Ok, Result};
-use btc_lib::{crypto::PublicKey, network::Message, types::Block, util::Saveable};
+use btc_lib::{
+    crypto::PublicKey,
+    network::{Client, InvItem, Message, Version},
+    params::{ChainParams, Network},
+    types::Block,
+    util::Saveable,
+};
 use clap::Parser;
-use tokio::{net::TcpStream, sync::Mutex, time::interval};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::interval,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 struct Cli {
+    /// network to mine on: mainnet, testnet or regtest
+    #[arg(short, long, default_value_t = Network::Mainnet)]
+    network: Network,
+    /// node address, defaults to the network's port on localhost
     #[arg(short, long)]
-    address: String,
+    address: Option<String>,
     #[arg(short, long)]
     public_key_file: String,
 }
 
 struct Miner {
     public_key: PublicKey,
-    stream: Mutex<TcpStream>,
+    client: Client,
+    // blocks and transactions the node announces
+    broadcasts: Mutex<mpsc::UnboundedReceiver<Message>>,
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
     mining: Arc<AtomicBool>,
     mined_block_sender: flume::Sender<Block>,
     mined_block_receiver: flume::Receiver<Block>,
+    // how often to fetch or validate the template
+    template_interval: Duration,
 }
 
 impl Miner {
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+    async fn new(address: String, public_key: PublicKey, params: &ChainParams) -> Result<Self> {
+        let user_agent = format!("/rsbtc-miner:{}/", env!("CARGO_PKG_VERSION"));
+        let version = Version::new(params, 0, user_agent, 0);
+        let (client, broadcasts) = Client::connect(&address, params, version).await?;
+        let peer = client.peer_version();
+        println!(
+            "connected to {} at height {}",
+            peer.user_agent, peer.best_height
+        );
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
 
         Ok(Self {
             public_key,
-            stream: Mutex::new(stream),
+            client,
+            broadcasts: Mutex::new(broadcasts),
             current_template: Arc::new(std::sync::Mutex::new(None)),
             mining: Arc::new(AtomicBool::new(false)),
             mined_block_sender,
             mined_block_receiver,
+            // don't wait longer than a block takes on fast networks
+            template_interval: Duration::from_secs(params.ideal_block_time.clamp(1, 5)),
         })
     }
 
     async fn run(&self) -> Result<()> {
         self.spawn_mining_thread();
-        let mut template_interval = interval(Duration::from_secs(5));
+        let mut template_interval = interval(self.template_interval);
+        let mut broadcasts = self.broadcasts.lock().await;
 
         loop {
             let receiver_clone = self.mined_block_receiver.clone();
@@ -60,6 +86,17 @@ impl Miner {
                 Result::Ok(mined_block) = receiver_clone.recv_async() => {
                     self.submit_block(mined_block).await?;
                 }
+                broadcast = broadcasts.recv() => match broadcast {
+                    // the template no longer extends the tip, fetch a new one
+                    Some(Message::Inv(items))
+                        if items.iter().any(|item| matches!(item, InvItem::Block(_))) =>
+                    {
+                        println!("node announced a new block");
+                        self.mining.store(false, Ordering::Relaxed);
+                    }
+                    Some(_) => {}
+                    None => return Err(anyhow!("connection to the node closed")),
+                }
             }
         }
     }
@@ -96,13 +133,8 @@ impl Miner {
     async fn fetch_template(&self) -> Result<()> {
         println!("Fetching new template");
         let message = Message::FetchTemplate(self.public_key.clone());
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
-        drop(stream_lock);
-        let mut stream_lock = self.stream.lock().await;
-        match Message::receive_async(&mut *stream_lock).await? {
+        match self.client.request(message).await? {
             Message::Template(template) => {
-                drop(stream_lock);
                 println!(
                     "Received new template with target: {}",
                     template.header.target
@@ -118,16 +150,11 @@ impl Miner {
     }
 
     async fn validate_template(&self) -> Result<()> {
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
+        let template = self.current_template.lock().unwrap().clone();
+        if let Some(template) = template {
             let message = Message::ValidateTemplate(template);
-            let mut stream_lock = self.stream.lock().await;
-            message.send_async(&mut *stream_lock).await?;
-            drop(stream_lock);
-
-            let mut stream_lock = self.stream.lock().await;
-            match Message::receive_async(&mut *stream_lock).await? {
+            match self.client.request(message).await? {
                 Message::TemplateValidity(valid) => {
-                    drop(stream_lock);
                     if !valid {
                         println!("current template is no longer valid");
                         self.mining.store(false, Ordering::Relaxed);
@@ -148,21 +175,12 @@ impl Miner {
     async fn submit_block(&self, block: Block) -> Result<()> {
         println!("Submitting mined block");
         let message = Message::SubmitTemplate(block);
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
+        self.client.send(message).await?;
         self.mining.store(false, Ordering::Relaxed);
         Ok(())
     }
 }
 
-fn usage() -> ! {
-    eprintln!(
-        "Usage: {} <address> <public_key_file>",
-        env::args().next().unwrap()
-    );
-    exit(1);
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -170,6 +188,11 @@ async fn main() -> Result<()> {
     let public_key = PublicKey::load_from_file(&cli.public_key_file)
         .map_err(|e| anyhow!("error reading public key file: {}", e))?;
 
-    let miner = Miner::new(cli.address, public_key).await?;
+    let params = ChainParams::for_network(cli.network);
+    let address = cli
+        .address
+        .unwrap_or_else(|| format!("127.0.0.1:{}", params.default_port));
+
+    let miner = Miner::new(address, public_key, &params).await?;
     miner.run().await
 }
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use btc_lib::{error::BtcError, util::Saveable};
+use chrono::{DateTime, TimeDelta, Utc};
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+
+// a peer reaching this score is banned
+const BAN_THRESHOLD: u32 = 100;
+// scores for each kind of misbehaviour
+const INVALID_BLOCK_SCORE: u32 = 100;
+const INVALID_TRANSACTION_SCORE: u32 = 10;
+const PROTOCOL_VIOLATION_SCORE: u32 = 20;
+// how long bans last unless told otherwise
+pub const DEFAULT_BAN_DURATION_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ban {
+    pub until: DateTime<Utc>,
+    // the misbehaviour that crossed the threshold
+    pub reason: String,
+}
+
+// the persisted bans, expired ones are left out
+#[derive(Serialize, Deserialize, Default)]
+pub struct BanList {
+    pub bans: HashMap<IpAddr, Ban>,
+}
+
+impl Saveable for BanList {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize ban list"))
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "failed to serialize ban list"))
+    }
+}
+
+// misbehaviour scores and bans of peers. scores are kept per connection,
+// by socket address, so one connection doesn't count against the others
+// from the same host. bans are kept by IP address, so reconnecting from
+// another port doesn't get around them. loopback and whitelisted
+// addresses are never scored or banned
+pub struct BanManager {
+    scores: DashMap<SocketAddr, u32>,
+    bans: DashMap<IpAddr, Ban>,
+    whitelist: DashSet<IpAddr>,
+    ban_duration_secs: AtomicI64,
+}
+
+impl BanManager {
+    pub fn new() -> Self {
+        BanManager {
+            scores: DashMap::new(),
+            bans: DashMap::new(),
+            whitelist: DashSet::new(),
+            ban_duration_secs: AtomicI64::new(DEFAULT_BAN_DURATION_SECS),
+        }
+    }
+
+    pub fn set_ban_duration(&self, secs: i64) {
+        self.ban_duration_secs.store(secs, Ordering::Relaxed);
+    }
+
+    pub fn whitelist(&self, ip: IpAddr) {
+        self.whitelist.insert(ip);
+    }
+
+    fn is_exempt(&self, ip: &IpAddr) -> bool {
+        ip.is_loopback() || self.whitelist.contains(ip)
+    }
+
+    pub fn load_ban_list(&self, ban_list: BanList) {
+        for (ip, ban) in ban_list.bans {
+            self.bans.insert(ip, ban);
+        }
+    }
+
+    pub fn ban_list(&self) -> BanList {
+        let now = Utc::now();
+        BanList {
+            bans: self
+                .bans
+                .iter()
+                .filter(|ban| ban.until > now)
+                .map(|ban| (*ban.key(), ban.value().clone()))
+                .collect(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        !self.is_exempt(ip) && self.bans.get(ip).is_some_and(|ban| ban.until > Utc::now())
+    }
+
+    // the connection closed, its score goes with it
+    pub fn forget(&self, peer: &SocketAddr) {
+        self.scores.remove(peer);
+    }
+
+    // add to a connection's score, banning and disconnecting its host once
+    // the score reaches BAN_THRESHOLD. returns true if the peer is banned
+    pub fn misbehaved(&self, peer: SocketAddr, score: u32, reason: &str) -> bool {
+        if self.is_exempt(&peer.ip()) {
+            println!("{} misbehaved: {}, not scored", peer, reason);
+            return false;
+        }
+        let total = {
+            let mut total = self.scores.entry(peer).or_insert(0);
+            *total += score;
+            *total
+        };
+        println!("{} misbehaved: {}, score {}", peer, reason, total);
+        if total < BAN_THRESHOLD {
+            return false;
+        }
+
+        self.scores.remove(&peer);
+        let ip = peer.ip();
+        let duration = TimeDelta::seconds(self.ban_duration_secs.load(Ordering::Relaxed));
+        let ban = Ban {
+            until: Utc::now() + duration,
+            reason: reason.to_string(),
+        };
+        println!("banning {} until {}", ip, ban.until);
+        self.bans.insert(ip, ban);
+        crate::PEERS.disconnect_ip(ip);
+        true
+    }
+
+    // score a block the peer sent and we rejected, true if the peer is banned
+    pub fn invalid_block(&self, peer: SocketAddr, e: &BtcError) -> bool {
+        e.is_misbehavior()
+            && self.misbehaved(
+                peer,
+                INVALID_BLOCK_SCORE,
+                &format!("sent an invalid block: {e}"),
+            )
+    }
+
+    // score a transaction the peer sent and we rejected, true if the peer is banned
+    pub fn invalid_transaction(&self, peer: SocketAddr, e: &BtcError) -> bool {
+        e.is_misbehavior()
+            && self.misbehaved(
+                peer,
+                INVALID_TRANSACTION_SCORE,
+                &format!("sent an invalid transaction: {e}"),
+            )
+    }
+
+    // score a message no honest peer would send, true if the peer is banned
+    pub fn protocol_violation(&self, peer: SocketAddr, reason: &str) -> bool {
+        self.misbehaved(peer, PROTOCOL_VIOLATION_SCORE, reason)
+    }
+}
+
+impl Default for BanManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,271 @@
+use std::net::SocketAddr;
+
+use btc_lib::{
+    network::{Envelope, Message, MAX_HEADERS_PER_MESSAGE},
+    params::Network,
+    sha256::Hash,
+};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+};
+
+// the inbound side of a peer's connection
+struct Connection {
+    peer: SocketAddr,
+    // identifies the peer to the peer manager
+    key: String,
+    replies: mpsc::UnboundedSender<Envelope>,
+}
+
+impl Connection {
+    // answer a request, false if the connection should be closed
+    fn reply(&self, request_id: Option<u64>, message: Message) -> bool {
+        let Some(id) = request_id else {
+            println!("received a request without an id, closing connection");
+            self.protocol_violation("sent a request without an id");
+            return false;
+        };
+        self.replies
+            .send(Envelope::Response { id, message })
+            .is_ok()
+    }
+
+    // true if the peer is banned for it
+    fn protocol_violation(&self, reason: &str) -> bool {
+        crate::BANS.protocol_violation(self.peer, reason)
+    }
+}
+
+pub async fn handle_connection(mut socket: TcpStream, peer: SocketAddr) {
+    let (params, version) = crate::util::local_version().await;
+    let network = params.network;
+    match Message::handshake(&params, version, &mut socket).await {
+        Ok(peer) => println!(
+            "handshake complete with {} at height {}",
+            peer.user_agent, peer.best_height
+        ),
+        Err(e) => {
+            println!("handshake failed: {e}, closing connection");
+            if e.is_misbehavior() {
+                crate::BANS.protocol_violation(peer, &format!("broke the handshake: {e}"));
+            }
+            return;
+        }
+    }
+
+    // replies and gossip are written by a separate task, so gossip
+    // can go out while we wait for the peer's next message
+    let (mut reader, writer) = socket.into_split();
+    let (replies, replies_receiver) = mpsc::unbounded_channel();
+    let connection = Connection {
+        peer,
+        key: peer.to_string(),
+        replies,
+    };
+    let gossip = crate::PEERS.register(&connection.key);
+    tokio::spawn(write_frames(network, writer, replies_receiver, gossip));
+
+    serve(network, &mut reader, &connection).await;
+    // closing both channels ends the writer once it sent what's queued
+    crate::PEERS.unregister(&connection.key);
+    crate::BANS.forget(&peer);
+}
+
+async fn write_frames(
+    network: Network,
+    mut writer: OwnedWriteHalf,
+    mut replies: mpsc::UnboundedReceiver<Envelope>,
+    mut gossip: mpsc::UnboundedReceiver<Message>,
+) {
+    loop {
+        let envelope = tokio::select! {
+            Some(envelope) = replies.recv() => envelope,
+            Some(message) = gossip.recv() => Envelope::Unsolicited(message),
+            else => break,
+        };
+        if envelope.send_async(network, &mut writer).await.is_err() {
+            break;
+        }
+    }
+}
+
+// handle the peer's messages until it disconnects or misbehaves
+async fn serve(network: Network, reader: &mut OwnedReadHalf, connection: &Connection) {
+    let key = connection.key.as_str();
+    loop {
+        // read a message from the socket
+        let envelope = match Envelope::receive_async(network, reader).await {
+            Ok(envelope) => envelope,
+            Err(e) if e.is_misbehavior() => {
+                println!("peer sent a bad frame: {e}, closing connection");
+                connection.protocol_violation(&format!("sent a bad frame: {e}"));
+                return;
+            }
+            Err(e) => {
+                println!("invalid message from peer: {e}, closing connection");
+                return;
+            }
+        };
+        // the peer may have been banned over another connection
+        if crate::BANS.is_banned(&connection.peer.ip()) {
+            println!("{} is banned, closing connection", connection.peer);
+            return;
+        }
+
+        // requests carry an id that goes back with the reply
+        let (request_id, message) = match envelope {
+            Envelope::Request { id, message } => (Some(id), message),
+            Envelope::Unsolicited(message) => (None, message),
+            Envelope::Response { .. } => {
+                println!("received a response without a request, closing connection");
+                connection.protocol_violation("sent a response without a request");
+                return;
+            }
+        };
+
+        use btc_lib::network::Message::*;
+        match message {
+            Version(_) | VerAck => {
+                println!("received a handshake message after the handshake, closing connection");
+                connection.protocol_violation("sent a handshake message after the handshake");
+                return;
+            }
+            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_)
+            | Headers(_) | NotFound(_) | TransactionStatus(_) | FeeRate(_) => {
+                println!("received a response message as a request, closing connection");
+                connection.protocol_violation("sent a response message as a request");
+                return;
+            }
+            FetchBlock(hash) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = match blockchain.block(&hash) {
+                    Some(block) => NewBlock(block.clone()),
+                    None => NotFound(hash),
+                };
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            GetHeaders(locator) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let headers = blockchain.headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                let message = Headers(headers);
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            DiscoverNodes => {
+                let message = NodeList(crate::PEERS.known_addresses());
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            AskDifference(height) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let count = blockchain.block_height() as i32 - height as i32;
+                let message = Difference(count);
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            FetchFeeRate => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = FeeRate(blockchain.mempool().min_fee(1000).to_sat());
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            FetchUTXOs(key) => {
+                println!("received request to fetch UTXOs");
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let utxos = crate::util::utxos_of(&blockchain, &key)
+                    .into_iter()
+                    .map(|(_, output, spent)| (output, spent))
+                    .collect();
+                let message = UTXOs(utxos);
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            Inv(items) => crate::relay::handle_inv(items, key).await,
+            GetData(items) => crate::relay::handle_get_data(items, key).await,
+            NewBlock(block) => {
+                println!("received new block");
+                if let Err(e) = crate::relay::accept_relayed_block(block, key).await {
+                    println!("block rejected: {e}");
+                    if crate::BANS.invalid_block(connection.peer, &e) {
+                        return;
+                    }
+                }
+            }
+            NewTransaction(transaction) => {
+                println!("received new transaction");
+                if let Err(e) = crate::relay::accept_transaction(transaction, key).await {
+                    println!("transaction rejected: {e}");
+                    if crate::BANS.invalid_transaction(connection.peer, &e) {
+                        return;
+                    }
+                }
+            }
+            SubmitTransaction(transaction) => {
+                println!("received transaction submission");
+                // submitted by a local wallet, a rejection isn't misbehaviour
+                // but is sent back
+                let status = match crate::relay::accept_transaction(transaction, key).await {
+                    Ok(()) => {
+                        println!("added transaction to mempool");
+                        Ok(())
+                    }
+                    Err(e) => {
+                        println!("transaction rejected: {e}");
+                        Err(e.to_string())
+                    }
+                };
+                if !connection.reply(request_id, TransactionStatus(status)) {
+                    return;
+                }
+            }
+            ValidateTemplate(template) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let status = template.header.prev_block_hash
+                    == blockchain
+                        .blocks()
+                        .last()
+                        .map(|last_block| last_block.hash())
+                        .unwrap_or(Hash::zero());
+                let message = TemplateValidity(status);
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+            SubmitTemplate(block) => {
+                println!("received mined template");
+                // a miner's template goes stale whenever a block arrives
+                // first, so a rejection isn't misbehaviour
+                match crate::relay::accept_block(block, key).await {
+                    Ok(()) => println!("block accepted"),
+                    Err(e) => println!("block rejected: {e}"),
+                }
+            }
+            FetchTemplate(pubkey) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let block = match blockchain.build_template(pubkey) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        println!("failed to build template: {e}");
+                        return;
+                    }
+                };
+
+                let message = Template(block);
+                if !connection.reply(request_id, message) {
+                    return;
+                }
+            }
+        }
+    }
+}
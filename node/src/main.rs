@@ -1,160 +1,232 @@
-use anyhow::{Context, Ok, Result};
+use anyhow::Result;
 use argh::FromArgs;
-use btc_lib::network::Message;
+use bans::{BanList, BanManager};
+use btc_lib::params::{ChainParams, Network};
 use btc_lib::types::Blockchain;
-use dashmap::DashMap;
+use btc_lib::util::Saveable;
+use peers::PeerManager;
 use static_init::dynamic;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
-
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, RwLock};
+
+mod bans;
+mod handler;
+mod peers;
+mod relay;
+mod rpc;
+mod sync;
 mod util;
 
 #[dynamic]
-pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
+pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::default());
+
+// known nodes and our connections to them
+#[dynamic]
+pub static PEERS: PeerManager = PeerManager::new();
 
-//nodes pool
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+// misbehaving peers and the ones banned for it
+#[dynamic]
+pub static BANS: BanManager = BanManager::new();
 
 #[derive(FromArgs)]
 /// A toy blockchain node
 struct Args {
-    #[argh(option, default = "9000")]
-    /// port number
-    port: u16,
+    #[argh(option, default = "Network::Mainnet")]
+    /// network to join: mainnet, testnet or regtest
+    network: Network,
+    #[argh(option)]
+    /// port number, defaults to the network's port
+    port: Option<u16>,
+    #[argh(option)]
+    /// port of the JSON-RPC interface on localhost, defaults to the network's RPC port
+    rpc_port: Option<u16>,
     #[argh(option, default = "String::from(\"./blockchain.cbor\")")]
     /// blockchain file location
     blockchain_file: String,
+    #[argh(option, default = "String::from(\"./mempool.cbor\")")]
+    /// mempool file location
+    mempool_file: String,
+    #[argh(option, default = "30")]
+    /// seconds between periodic blockchain saves, 0 to save only on shutdown
+    save_interval: u64,
+    #[argh(option, default = "String::from(\"./peers.cbor\")")]
+    /// address book file location
+    peers_file: String,
+    #[argh(option, default = "peers::DEFAULT_MAX_OUTBOUND")]
+    /// number of nodes to keep outbound connections to
+    max_outbound: usize,
+    #[argh(option, default = "String::from(\"./bans.cbor\")")]
+    /// ban list file location
+    bans_file: String,
+    #[argh(option, default = "bans::DEFAULT_BAN_DURATION_SECS")]
+    /// seconds a misbehaving peer stays banned
+    ban_duration: i64,
+    #[argh(option)]
+    /// IP address never scored or banned, loopback never is either. can be repeated
+    whitelist: Vec<IpAddr>,
+    #[argh(switch)]
+    /// print the banned peers and exit
+    list_bans: bool,
+    #[argh(switch)]
+    /// lift all bans and exit
+    clear_bans: bool,
+    #[argh(switch)]
+    /// index transactions by hash and outputs by public key
+    index: bool,
+    #[argh(option, default = "btc_lib::MAX_MEMPOOL_SIZE")]
+    /// max size of the mempool in bytes
+    max_mempool_size: usize,
+    #[argh(option, default = "btc_lib::MIN_RELAY_FEE_RATE")]
+    /// minimum fee rate in satoshis per byte for mempool transactions
+    min_relay_fee_rate: u64,
     #[argh(positional)]
     /// addresses of initial nodes
     nodes: Vec<String>,
 }
 
-pub async fn populate_connections(nodes: &[String]) -> Result<()> {
-    println!("trying to connect to other nodes...");
-
-    for node in nodes {
-        println!("connecting to node: {}", node);
-        let mut stream = TcpStream::connect(&node).await?;
-
-        let message = Message::DiscoverNodes;
-
-        message.send_async(&mut stream).await?;
-        println!("send DiscoverNode to {}", node);
+#[tokio::main]
+async fn main() -> Result<()> {
+    // parse command line arguments
+    let args: Args = argh::from_env();
+    let params = ChainParams::for_network(args.network);
+    let port = args.port.unwrap_or(params.default_port);
+    let rpc_port = args.rpc_port.unwrap_or(params.default_rpc_port);
+    let blockchain_file = args.blockchain_file;
+    let save_interval = args.save_interval;
+    let nodes = args.nodes;
 
-        let message = Message::receive_async(&mut stream).await?;
+    if args.list_bans || args.clear_bans {
+        return manage_bans(&args.bans_file, args.clear_bans);
+    }
 
-        match message {
-            Message::NodeList(child_nodes) => {
-                println!("recived node list from {}", node);
+    println!("running on {}", params.network);
+    *BLOCKCHAIN.write().await = Blockchain::new(params.clone());
 
-                for child_node in child_nodes {
-                    println!("adding node {}", &child_node);
+    if Path::new(&blockchain_file).exists() {
+        util::load_blockchain(&blockchain_file, &params).await?;
+    } else {
+        println!("blockchain file does not exist");
+        // every chain of the network starts from the same hard-coded block
+        let mut blockchain = BLOCKCHAIN.write().await;
+        blockchain.add_block(params.genesis_block())?;
+    }
+    if args.index {
+        println!("building indexes...");
+        BLOCKCHAIN.write().await.enable_index();
+        println!("indexes built");
+    }
 
-                    let new_stream = TcpStream::connect(&child_node).await?;
+    BANS.set_ban_duration(args.ban_duration);
+    for ip in args.whitelist {
+        BANS.whitelist(ip);
+    }
+    if Path::new(&args.bans_file).exists() {
+        util::load_ban_list(&args.bans_file)?;
+    }
+    PEERS.set_max_outbound(args.max_outbound);
+    if Path::new(&args.peers_file).exists() {
+        util::load_address_book(&args.peers_file)?;
+    }
+    for node in &nodes {
+        PEERS.add_address(node);
+    }
+    println!("trying to connect to other nodes...");
+    PEERS.maintain().await;
+    println!("connected to {} nodes", PEERS.connected().len());
+    if PEERS.connected().is_empty() {
+        println!("no nodes reachable, starting as a seed node");
+    } else {
+        // catch up with the chain of the other nodes
+        sync::sync_blockchain().await;
+    }
 
-                    crate::NODES.insert(child_node, new_stream);
-                }
-            }
-            _ => {
-                println!("unexpected message from {}", node);
-            }
-        }
-        crate::NODES.insert(node.clone(), stream);
+    BLOCKCHAIN
+        .write()
+        .await
+        .set_mempool_limits(args.max_mempool_size, args.min_relay_fee_rate);
+    if Path::new(&args.mempool_file).exists() {
+        util::load_mempool(&args.mempool_file).await?;
     }
-    Ok(())
-}
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    println!("finding nodes with the highest blockchain length..");
+    // start listening for incoming connections
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    println!("listening on {}", addr);
 
-    let mut longest_name = String::new();
-    let mut longest_count = 0;
-    let all_nodes = crate::NODES
-        .iter()
-        .map(|x| x.key().clone())
-        .collect::<Vec<_>>();
-
-    for node in all_nodes {
-        println!("asking {} for blockchain length", node);
-
-        let mut stream = crate::NODES.get_mut(&node).context("no node")?;
-        let message = Message::AskDifference(0);
-        message.send_async(&mut *stream).await.unwrap();
-        println!("sent AskDifference to {}", node);
-        let message = Message::receive_async(&mut *stream).await?;
-
-        match message {
-            Message::Difference(count) => {
-                println!("received Difference from {}", node);
-                if count > longest_count {
-                    println!("new longest blockchain {} blocks from {node}", count);
-                    longest_count = count;
-                    longest_name = node;
+    // periodically save the blockchain
+    if save_interval > 0 {
+        tokio::spawn(util::save(blockchain_file.clone(), save_interval));
+    }
+    // keep connected to other nodes
+    tokio::spawn(PEERS.run(args.peers_file.clone(), args.bans_file.clone()));
+
+    // serve the JSON-RPC interface to local tools
+    let (stop_sender, mut stop_receiver) = mpsc::channel(1);
+    let rpc_addr = SocketAddr::from(([127, 0, 0, 1], rpc_port));
+    tokio::spawn(async move {
+        if let Err(e) = rpc::serve(rpc_addr, stop_sender).await {
+            println!("rpc interface failed: {}", e);
+        }
+    });
+
+    let shutdown = util::shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                let (socket, peer) = result?;
+                if BANS.is_banned(&peer.ip()) {
+                    println!("refusing connection from banned {}", peer);
+                    continue;
                 }
+                println!("accepted connection from {}", peer);
+                tokio::spawn(handler::handle_connection(socket, peer));
             }
-            e => {
-                println!("unexpected message from {} : {:?}", node, e);
+            result = &mut shutdown => {
+                result?;
+                println!("shutting down");
+                break;
             }
-        }
-    }
-    Ok((longest_name, longest_count as u32))
-}
-
-pub async fn download_blockchain(node: &str, count: u32) -> Result<()> {
-    let mut stream = crate::NODES.get_mut(node).unwrap();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
-        message.send_async(&mut *stream).await?;
-        let message = Message::receive_async(&mut *stream).await?;
-
-        match message {
-            Message::NewBlock(block) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                blockchain.add_block(block)?;
-            }
-            _ => {
-                println!("unexpected message from {}", node);
+            _ = stop_receiver.recv() => {
+                println!("shutting down on rpc request");
+                break;
             }
         }
     }
-    Ok(())
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // parse command line arguments
-    let args: Args = argh::from_env();
-    let port = args.port;
-    let blockchain_file = args.blockchain_file;
-    let nodes = args.nodes;
+    util::save_blockchain(&blockchain_file).await?;
+    util::save_mempool(&args.mempool_file).await?;
+    util::save_address_book(&args.peers_file)?;
+    util::save_ban_list(&args.bans_file)
+}
 
-    if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file);
+// print the bans in the ban list file, lifting them if asked to
+fn manage_bans(bans_file: &str, clear: bool) -> Result<()> {
+    let ban_list = if Path::new(bans_file).exists() {
+        BanList::load_from_file(bans_file)?
     } else {
-        println!("blockchain file does not exist");
-        populate_connections(&nodes).await?;
-        println!("total number of nodes known: {}", NODES.len());
-        if nodes.is_empty() {
-            println!("no initial nodes provided, starting as a seed node ");
-        } else {
-            let (longest_name, longest_count) = find_longest_chain_node().await?;
-            // request the blockchain from the node with the longest chain
-            download_blockchain(&longest_name, longest_count).await?;
-            println!("blockchain download from {}", longest_name);
-
-            // recalculate utxos
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();
-            }
+        BanList::default()
+    };
+    let now = chrono::Utc::now();
+    let mut bans: Vec<_> = ban_list
+        .bans
+        .iter()
+        .filter(|(_, ban)| ban.until > now)
+        .collect();
+    bans.sort_by_key(|(_, ban)| ban.until);
+    if bans.is_empty() {
+        println!("no banned peers");
+    }
+    for (ip, ban) in bans {
+        println!("{} banned until {}: {}", ip, ban.until, ban.reason);
+    }
 
-            // try to adjust difficulty
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.try_adjust_target();
-            }
-        }
+    if clear {
+        BanList::default().save_to_file(bans_file)?;
+        println!("cleared all bans");
     }
     Ok(())
 }
@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use btc_lib::{
+    network::{Client, Message},
+    util::Saveable,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    time::{self, Duration},
+};
+
+// outbound connections kept open unless told otherwise
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+// an address is forgotten after this many failed connections in a row
+const MAX_FAILURES: u32 = 10;
+// wait before reconnecting, doubled after every failure
+const RECONNECT_DELAY_SECS: i64 = 5;
+const MAX_RECONNECT_DELAY_SECS: i64 = 600;
+// seconds between checks of the outbound connections
+const MAINTENANCE_INTERVAL_SECS: u64 = 10;
+// most addresses taken from or sent in a single NodeList
+pub const MAX_ADDRESSES_PER_MESSAGE: usize = 1_000;
+// most addresses kept in the address book
+const MAX_ADDRESSES: usize = 10_000;
+// an address not seen for this long may be forgotten
+const STALE_AFTER_DAYS: i64 = 30;
+
+// what we know about a node address, kept across restarts
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PeerInfo {
+    // last time we heard from the node
+    pub last_seen: Option<DateTime<Utc>>,
+    // height the node announced when we connected
+    pub best_height: u64,
+    pub user_agent: String,
+    // failed connection attempts since the last successful one
+    pub failures: u32,
+    // no connection is attempted before this
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+impl PeerInfo {
+    // failing, never reached or not heard from in a long time
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        self.failures > 0
+            || self
+                .last_seen
+                .is_none_or(|last_seen| now - last_seen > TimeDelta::days(STALE_AFTER_DAYS))
+    }
+
+    fn record_failure(&mut self) {
+        self.failures += 1;
+        let delay = RECONNECT_DELAY_SECS
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_RECONNECT_DELAY_SECS);
+        self.retry_at = Some(Utc::now() + TimeDelta::seconds(delay));
+    }
+}
+
+// the persisted part of the peer manager
+#[derive(Serialize, Deserialize, Default)]
+pub struct AddressBook {
+    peers: HashMap<String, PeerInfo>,
+}
+
+impl Saveable for AddressBook {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize address book",
+            )
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "failed to serialize address book"))
+    }
+}
+
+// every node address we know about and the outbound connections to them
+pub struct PeerManager {
+    peers: DashMap<String, PeerInfo>,
+    connections: DashMap<String, Arc<Client>>,
+    // messages to send to every connected peer, inbound or outbound.
+    // outbound peers are keyed by address, inbound ones by socket address
+    outgoing: DashMap<String, mpsc::UnboundedSender<Message>>,
+    max_outbound: AtomicUsize,
+}
+
+impl PeerManager {
+    pub fn new() -> Self {
+        PeerManager {
+            peers: DashMap::new(),
+            connections: DashMap::new(),
+            outgoing: DashMap::new(),
+            max_outbound: AtomicUsize::new(DEFAULT_MAX_OUTBOUND),
+        }
+    }
+
+    pub fn set_max_outbound(&self, max_outbound: usize) {
+        self.max_outbound.store(max_outbound, Ordering::Relaxed);
+    }
+
+    pub fn load_address_book(&self, address_book: AddressBook) {
+        for (address, peer) in address_book.peers.into_iter().take(MAX_ADDRESSES) {
+            self.peers.insert(address, peer);
+        }
+    }
+
+    pub fn address_book(&self) -> AddressBook {
+        AddressBook {
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| (peer.key().clone(), peer.value().clone()))
+                .collect(),
+        }
+    }
+
+    // remember an address without connecting to it
+    pub fn add_address(&self, address: &str) {
+        self.peers.entry(address.to_string()).or_default();
+    }
+
+    // remember the addresses a node told us about, at most
+    // MAX_ADDRESSES_PER_MESSAGE of them. once the address book is full
+    // a new address only takes the place of a stale one
+    fn learn_addresses(&self, addresses: Vec<String>) {
+        for address in addresses.into_iter().take(MAX_ADDRESSES_PER_MESSAGE) {
+            if self.peers.contains_key(&address) {
+                continue;
+            }
+            if self.peers.len() >= MAX_ADDRESSES && !self.evict_stale() {
+                return;
+            }
+            self.add_address(&address);
+        }
+    }
+
+    // forget the stale address we are least likely to reach, false if
+    // there is none
+    fn evict_stale(&self) -> bool {
+        let now = Utc::now();
+        let stalest = self
+            .peers
+            .iter()
+            .filter(|peer| !self.connections.contains_key(peer.key()))
+            .filter(|peer| peer.is_stale(now))
+            .max_by_key(|peer| (peer.failures, std::cmp::Reverse(peer.last_seen)))
+            .map(|peer| peer.key().clone());
+        match stalest {
+            Some(address) => {
+                self.peers.remove(&address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // addresses we managed to connect to at some point, at most
+    // MAX_ADDRESSES_PER_MESSAGE of them, the most recently seen first
+    pub fn known_addresses(&self) -> Vec<String> {
+        let mut known: Vec<(DateTime<Utc>, String)> = self
+            .peers
+            .iter()
+            .filter_map(|peer| Some((peer.last_seen?, peer.key().clone())))
+            .collect();
+        known.sort_unstable_by(|a, b| b.cmp(a));
+        known
+            .into_iter()
+            .take(MAX_ADDRESSES_PER_MESSAGE)
+            .map(|(_, address)| address)
+            .collect()
+    }
+
+    // every known address and what we know about it
+    pub fn peers(&self) -> Vec<(String, PeerInfo)> {
+        self.peers
+            .iter()
+            .map(|peer| (peer.key().clone(), peer.value().clone()))
+            .collect()
+    }
+
+    // the node sent us something
+    pub fn seen(&self, address: &str) {
+        if let Some(mut peer) = self.peers.get_mut(address) {
+            peer.last_seen = Some(Utc::now());
+        }
+    }
+
+    pub fn get(&self, address: &str) -> Option<Arc<Client>> {
+        self.connections.get(address).map(|client| client.clone())
+    }
+
+    // addresses of the open outbound connections
+    pub fn connected(&self) -> Vec<String> {
+        self.connections
+            .iter()
+            .map(|client| client.key().clone())
+            .collect()
+    }
+
+    // socket addresses of the peers connected to us
+    pub fn inbound(&self) -> Vec<String> {
+        self.outgoing
+            .iter()
+            .filter(|sender| !self.connections.contains_key(sender.key()))
+            .map(|sender| sender.key().clone())
+            .collect()
+    }
+
+    // the connected nodes, those that announced the most blocks first
+    pub fn best_peers(&self) -> Vec<String> {
+        let mut peers: Vec<(u64, String)> = self
+            .connections
+            .iter()
+            .map(|client| (client.peer_version().best_height, client.key().clone()))
+            .collect();
+        peers.sort_unstable_by(|a, b| b.cmp(a));
+        peers.into_iter().map(|(_, address)| address).collect()
+    }
+
+    // start queueing messages for a connected peer, the connection
+    // handler sends whatever arrives on the returned receiver
+    pub fn register(&self, key: &str) -> mpsc::UnboundedReceiver<Message> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.outgoing.insert(key.to_string(), sender);
+        receiver
+    }
+
+    pub fn unregister(&self, key: &str) {
+        self.outgoing.remove(key);
+    }
+
+    pub fn send_to(&self, key: &str, message: Message) {
+        if let Some(sender) = self.outgoing.get(key) {
+            // the connection may be closing
+            let _ = sender.send(message);
+        }
+    }
+
+    // send a message to every connected peer except one
+    pub fn broadcast(&self, message: Message, except: &str) {
+        for sender in self.outgoing.iter().filter(|sender| sender.key() != except) {
+            let _ = sender.send(message.clone());
+        }
+    }
+
+    // close the outbound connections to a host and stop sending gossip to
+    // its inbound ones, which close once the host sends something else
+    pub fn disconnect_ip(&self, ip: IpAddr) {
+        let outbound: Vec<String> = self
+            .connections
+            .iter()
+            .filter(|client| client.peer_addr().ip() == ip)
+            .map(|client| client.key().clone())
+            .collect();
+        for address in outbound {
+            println!("disconnecting from {}", address);
+            self.connections.remove(&address);
+            self.unregister(&address);
+        }
+
+        let inbound: Vec<String> = self
+            .outgoing
+            .iter()
+            .filter(|sender| {
+                sender
+                    .key()
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|peer| peer.ip() == ip)
+            })
+            .map(|sender| sender.key().clone())
+            .collect();
+        for key in inbound {
+            self.unregister(&key);
+        }
+    }
+
+    // connect to an address, learning the addresses it knows about
+    pub async fn connect(&self, address: &str) -> Result<()> {
+        let result = self.try_connect(address).await;
+        if let Err(e) = &result {
+            let forget = match self.peers.get_mut(address) {
+                Some(mut peer) => {
+                    peer.record_failure();
+                    peer.failures >= MAX_FAILURES
+                }
+                None => false,
+            };
+            println!("failed to connect to {}: {}", address, e);
+            if forget {
+                println!("forgetting {} after {} failures", address, MAX_FAILURES);
+                self.peers.remove(address);
+            }
+        }
+        result
+    }
+
+    async fn try_connect(&self, address: &str) -> Result<()> {
+        let client = crate::util::connect(address).await?;
+        if crate::BANS.is_banned(&client.peer_addr().ip()) {
+            return Err(anyhow!("{} is banned", address));
+        }
+        let peer_version = client.peer_version().clone();
+        if peer_version.nonce == *crate::util::LOCAL_NONCE {
+            self.peers.remove(address);
+            return Err(anyhow!("{} is this node", address));
+        }
+
+        let client = Arc::new(client);
+        self.connections.insert(address.to_string(), client.clone());
+        let mut outgoing = self.register(address);
+        let sender = client.clone();
+        tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                if sender.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        {
+            let mut peer = self.peers.entry(address.to_string()).or_default();
+            peer.last_seen = Some(Utc::now());
+            peer.best_height = peer_version.best_height;
+            peer.user_agent = peer_version.user_agent;
+            peer.failures = 0;
+            peer.retry_at = None;
+        }
+
+        match client.request(Message::DiscoverNodes).await? {
+            Message::NodeList(addresses) => {
+                println!("received {} addresses from {}", addresses.len(), address);
+                self.learn_addresses(addresses);
+            }
+            _ => println!("unexpected message from {}", address),
+        }
+        Ok(())
+    }
+
+    // the address to connect to next: fewest failures first, then the
+    // most recently seen
+    fn next_candidate(&self) -> Option<String> {
+        let now = Utc::now();
+        self.peers
+            .iter()
+            .filter(|peer| !self.connections.contains_key(peer.key()))
+            .filter(|peer| peer.retry_at.is_none_or(|retry_at| retry_at <= now))
+            .min_by_key(|peer| (peer.failures, std::cmp::Reverse(peer.last_seen)))
+            .map(|peer| peer.key().clone())
+    }
+
+    // drop closed connections and open new ones up to the outbound limit
+    pub async fn maintain(&self) {
+        let closed: Vec<String> = self
+            .connections
+            .iter()
+            .filter(|client| !client.is_connected())
+            .map(|client| client.key().clone())
+            .collect();
+        for address in closed {
+            println!("lost connection to {}", address);
+            self.connections.remove(&address);
+            self.unregister(&address);
+            if let Some(mut peer) = self.peers.get_mut(&address) {
+                peer.retry_at = Some(Utc::now() + TimeDelta::seconds(RECONNECT_DELAY_SECS));
+            }
+        }
+
+        while self.connections.len() < self.max_outbound.load(Ordering::Relaxed) {
+            let Some(address) = self.next_candidate() else {
+                break;
+            };
+            println!("connecting to node: {}", address);
+            // failures are recorded and retried later
+            let _ = self.connect(&address).await;
+        }
+    }
+
+    // keep the connections up and the address book and ban list saved
+    pub async fn run(&self, address_book_file: String, ban_list_file: String) {
+        let mut interval = time::interval(Duration::from_secs(MAINTENANCE_INTERVAL_SECS));
+        // the first tick completes immediately, skip it
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.maintain().await;
+            if let Err(e) = self.address_book().save_to_file(&address_book_file) {
+                println!("failed to save address book: {}", e);
+            }
+            if let Err(e) = crate::BANS.ban_list().save_to_file(&ban_list_file) {
+                println!("failed to save ban list: {}", e);
+            }
+        }
+    }
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use btc_lib::{
+    error::BtcError,
+    network::{InvItem, Message},
+    types::{Block, Transaction},
+};
+use static_init::dynamic;
+
+// how many accepted items are remembered
+const SEEN_CACHE_SIZE: usize = 10_000;
+// an item requested from a peer that doesn't arrive in time may be
+// requested from the next peer announcing it
+const IN_FLIGHT_TIMEOUT_SECS: u64 = 30;
+
+// items we already accepted, so they are neither requested nor announced
+// again when peers announce them back
+struct SeenCache {
+    items: HashSet<InvItem>,
+    // insertion order, the oldest item is forgotten first
+    order: VecDeque<InvItem>,
+}
+
+impl SeenCache {
+    fn new() -> Self {
+        SeenCache {
+            items: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, item: &InvItem) -> bool {
+        self.items.contains(item)
+    }
+
+    // false if the item was already seen
+    fn insert(&mut self, item: InvItem) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > SEEN_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[dynamic]
+static SEEN: Mutex<SeenCache> = Mutex::new(SeenCache::new());
+// items requested from a peer and not yet received, with when they were
+// requested. an item leaves once it arrives, accepted or not
+#[dynamic]
+static IN_FLIGHT: Mutex<HashMap<InvItem, Instant>> = Mutex::new(HashMap::new());
+
+// the item arrived, so it's no longer waited for. it's seen only once
+// accepted, so a rejected copy doesn't stop us from taking a valid one
+fn received(item: InvItem, accepted: bool) {
+    IN_FLIGHT.lock().unwrap().remove(&item);
+    if accepted {
+        SEEN.lock().unwrap().insert(item);
+    }
+}
+
+// add a block to the blockchain and announce it to every peer
+// except the one it came from
+pub async fn accept_block(block: Block, source: &str) -> Result<(), BtcError> {
+    let hash = block.hash();
+    let item = InvItem::Block(hash);
+    let result = crate::BLOCKCHAIN.write().await.add_block(block);
+    received(item, result.is_ok());
+    crate::util::log_block_outcome(&hash, result?);
+    crate::PEERS.broadcast(Message::Inv(vec![item]), source);
+    Ok(())
+}
+
+// accept a block a peer sent us. if we don't have its parent we are
+// behind the peer, so catch up with it in the background instead
+pub async fn accept_relayed_block(block: Block, source: &str) -> Result<(), BtcError> {
+    let orphan = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        blockchain.block_height() > 0 && !blockchain.contains_block(&block.header.prev_block_hash)
+    };
+    if !orphan {
+        return accept_block(block, source).await;
+    }
+    received(InvItem::Block(block.hash()), false);
+    tokio::spawn(crate::sync::catch_up(source.to_string(), block));
+    Ok(())
+}
+
+// add a transaction to the mempool and announce it to every peer
+// except the one it came from
+pub async fn accept_transaction(transaction: Transaction, source: &str) -> Result<(), BtcError> {
+    let item = InvItem::Transaction(transaction.hash());
+    let result = crate::BLOCKCHAIN.write().await.add_to_mempool(transaction);
+    received(item, result.is_ok());
+    result?;
+    crate::PEERS.broadcast(Message::Inv(vec![item]), source);
+    Ok(())
+}
+
+// ask the announcing peer for the items we have neither seen nor are
+// waiting for from another peer
+pub async fn handle_inv(items: Vec<InvItem>, source: &str) {
+    let wanted: Vec<InvItem> = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let seen = SEEN.lock().unwrap();
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(IN_FLIGHT_TIMEOUT_SECS);
+        in_flight.retain(|_, requested| now.duration_since(*requested) < timeout);
+        items
+            .into_iter()
+            .filter(|item| match item {
+                InvItem::Block(hash) => !blockchain.contains_block(hash),
+                InvItem::Transaction(hash) => !blockchain.mempool().contains(hash),
+            })
+            .filter(|item| !seen.contains(item))
+            .filter(|item| match in_flight.entry(*item) {
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(now);
+                    true
+                }
+            })
+            .collect()
+    };
+    if !wanted.is_empty() {
+        crate::PEERS.send_to(source, Message::GetData(wanted));
+    }
+}
+
+// send the requested items we have
+pub async fn handle_get_data(items: Vec<InvItem>, source: &str) {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    for item in items {
+        let message = match item {
+            InvItem::Block(hash) => blockchain.block(&hash).cloned().map(Message::NewBlock),
+            InvItem::Transaction(hash) => blockchain
+                .mempool()
+                .get(&hash)
+                .map(|entry| Message::NewTransaction(entry.transaction.clone())),
+        };
+        if let Some(message) = message {
+            crate::PEERS.send_to(source, message);
+        }
+    }
+}
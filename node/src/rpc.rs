@@ -0,0 +1,317 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use axum::{extract::State, routing::post, Json, Router};
+use btc_lib::{
+    crypto::PublicKey,
+    sha256::Hash,
+    types::{Block, OutputRecord, Transaction, TransactionOutput},
+    util::Saveable,
+};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::peers::PeerInfo;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+// the request was understood but can't be fulfilled
+const NOT_FOUND: i32 = -32001;
+const REJECTED: i32 = -32002;
+
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    // absent for notifications, which get a reply anyway
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// the parameters of each method, by name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockParams {
+    Hash { hash: Hash },
+    Height { height: usize },
+}
+
+#[derive(Deserialize)]
+struct HashParams {
+    hash: Hash,
+}
+
+#[derive(Deserialize)]
+struct PubkeyParams {
+    // PEM, as in the .pub.pem key files
+    pubkey: String,
+}
+
+#[derive(Deserialize)]
+struct BlockSubmission {
+    block: Block,
+}
+
+// the results that aren't library types
+#[derive(Serialize)]
+struct Tip {
+    height: u64,
+    hash: Hash,
+    target: String,
+    work: String,
+}
+
+#[derive(Serialize)]
+struct TransactionInfo {
+    transaction: Transaction,
+    // None while in the mempool
+    block_hash: Option<Hash>,
+    height: Option<usize>,
+    // index within the block
+    position: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MempoolInfo {
+    hash: Hash,
+    fee: u64,
+    size: usize,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct PeerEntry {
+    address: String,
+    connected: bool,
+    #[serde(flatten)]
+    info: PeerInfo,
+}
+
+#[derive(Serialize)]
+struct Peers {
+    known: Vec<PeerEntry>,
+    // socket addresses of the peers connected to us
+    inbound: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Utxo {
+    hash: Hash,
+    output: TransactionOutput,
+    // spent by a mempool transaction
+    spent: bool,
+}
+
+// serve JSON-RPC 2.0 requests POSTed to / until the node shuts down.
+// the stop method sends on stop
+pub async fn serve(addr: SocketAddr, stop: mpsc::Sender<()>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("rpc listening on {}", addr);
+    let app = Router::new().route("/", post(handle)).with_state(stop);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn handle(State(stop): State<mpsc::Sender<()>>, body: String) -> Json<Response> {
+    let (id, outcome) = match serde_json::from_str::<Request>(&body) {
+        Ok(request) => {
+            println!("rpc call: {}", request.method);
+            (
+                request.id,
+                call(&request.method, request.params, &stop).await,
+            )
+        }
+        Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    Json(Response {
+        jsonrpc: "2.0",
+        result,
+        error,
+        id,
+    })
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn to_value<T: Serialize>(result: T) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|e| RpcError::new(REJECTED, e.to_string()))
+}
+
+fn parse_pubkey(pem: &str) -> Result<PublicKey, RpcError> {
+    PublicKey::load(pem.as_bytes()).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+async fn call(
+    method: &str,
+    params_value: Value,
+    stop: &mpsc::Sender<()>,
+) -> Result<Value, RpcError> {
+    match method {
+        "get_tip" => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let hash = blockchain
+                .blocks()
+                .last()
+                .map(|block| block.hash())
+                .unwrap_or(Hash::zero());
+            to_value(Tip {
+                height: blockchain.block_height(),
+                hash,
+                target: format!("{:x}", blockchain.target()),
+                work: format!("{:x}", blockchain.work()),
+            })
+        }
+        "get_block" => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let block = match params(params_value)? {
+                BlockParams::Hash { hash } => blockchain.block(&hash),
+                BlockParams::Height { height } => blockchain.blocks().nth(height),
+            };
+            let block = block.ok_or_else(|| RpcError::new(NOT_FOUND, "no such block"))?;
+            to_value(block)
+        }
+        "get_transaction" => {
+            let HashParams { hash } = params(params_value)?;
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            if let Some(entry) = blockchain.mempool().get(&hash) {
+                return to_value(TransactionInfo {
+                    transaction: entry.transaction.clone(),
+                    block_hash: None,
+                    height: None,
+                    position: None,
+                });
+            }
+            let (transaction, location) = blockchain
+                .transaction(&hash)
+                .ok_or_else(|| RpcError::new(NOT_FOUND, "no such transaction"))?;
+            let block_hash = blockchain
+                .blocks()
+                .nth(location.height)
+                .map(|block| block.hash());
+            to_value(TransactionInfo {
+                transaction: transaction.clone(),
+                block_hash,
+                height: Some(location.height),
+                position: Some(location.position),
+            })
+        }
+        "get_mempool" => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let entries: Vec<MempoolInfo> = blockchain
+                .mempool()
+                .iter()
+                .map(|entry| MempoolInfo {
+                    hash: entry.transaction.hash(),
+                    fee: entry.fee.to_sat(),
+                    size: entry.size,
+                    timestamp: entry.timestamp,
+                })
+                .collect();
+            to_value(entries)
+        }
+        "get_peers" => {
+            let connected = crate::PEERS.connected();
+            let known = crate::PEERS
+                .peers()
+                .into_iter()
+                .map(|(address, info)| PeerEntry {
+                    connected: connected.contains(&address),
+                    address,
+                    info,
+                })
+                .collect();
+            to_value(Peers {
+                known,
+                inbound: crate::PEERS.inbound(),
+            })
+        }
+        "get_utxos" => {
+            let PubkeyParams { pubkey } = params(params_value)?;
+            let pubkey = parse_pubkey(&pubkey)?;
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let utxos: Vec<Utxo> = crate::util::utxos_of(&blockchain, &pubkey)
+                .into_iter()
+                .map(|(hash, output, spent)| Utxo {
+                    hash,
+                    output,
+                    spent,
+                })
+                .collect();
+            to_value(utxos)
+        }
+        "get_history" => {
+            let PubkeyParams { pubkey } = params(params_value)?;
+            let pubkey = parse_pubkey(&pubkey)?;
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let index = blockchain
+                .index()
+                .ok_or_else(|| RpcError::new(REJECTED, "the node runs without --index"))?;
+            let history: Vec<&OutputRecord> = index.outputs_of(&pubkey).collect();
+            to_value(history)
+        }
+        "get_template" => {
+            let PubkeyParams { pubkey } = params(params_value)?;
+            let pubkey = parse_pubkey(&pubkey)?;
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let template = blockchain
+                .build_template(pubkey)
+                .map_err(|e| RpcError::new(REJECTED, e.to_string()))?;
+            to_value(template)
+        }
+        "submit_block" => {
+            let BlockSubmission { block } = params(params_value)?;
+            let hash = block.hash();
+            // not a peer, so the block is announced to every peer
+            crate::relay::accept_block(block, "rpc")
+                .await
+                .map_err(|e| RpcError::new(REJECTED, e.to_string()))?;
+            println!("block {} submitted over rpc", hash);
+            to_value(hash)
+        }
+        "stop" => {
+            // a full channel means a stop is already on its way
+            let _ = stop.try_send(());
+            to_value("stopping")
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, Context, Result};
+use btc_lib::{
+    network::{Message, MAX_HEADERS_PER_MESSAGE},
+    sha256::Hash,
+    types::{Block, BlockOutcome, HeaderChain},
+};
+use tokio::task::JoinSet;
+
+// blocks requested at once, spread over all known nodes
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+
+// set while a catch_up runs, one is enough to fetch every missing block
+static CATCHING_UP: AtomicBool = AtomicBool::new(false);
+
+// sync from the node announcing the most blocks. a node that misbehaves,
+// fails or goes silent is scored and the next one is tried, so no single
+// node can stop the node from starting
+pub async fn sync_blockchain() {
+    let nodes = crate::PEERS.best_peers();
+    if nodes.is_empty() {
+        println!("no nodes to sync from");
+        return;
+    }
+    for node in nodes {
+        match sync_from(&node).await {
+            Ok(()) => break,
+            Err(e) => println!("failed to sync from {}: {:#}", node, e),
+        }
+    }
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    println!("blockchain synced to height {}", blockchain.block_height());
+}
+
+// a node sent a block whose parent we don't have. ask it for the headers
+// after our locator and download the missing blocks, then add the block.
+// a node that connected to us can't be asked, so our outbound nodes are
+pub async fn catch_up(source: String, block: Block) {
+    if CATCHING_UP.swap(true, Ordering::SeqCst) {
+        println!("already catching up, ignoring orphan block from {}", source);
+        return;
+    }
+    let nodes = match crate::PEERS.get(&source) {
+        Some(_) => vec![source.clone()],
+        None => crate::PEERS.best_peers(),
+    };
+    println!("block {} has an unknown parent, catching up", block.hash());
+    for node in nodes {
+        match sync_from(&node).await {
+            Ok(()) => break,
+            Err(e) => println!("failed to catch up with {}: {:#}", node, e),
+        }
+    }
+    CATCHING_UP.store(false, Ordering::SeqCst);
+
+    let hash = block.hash();
+    if crate::BLOCKCHAIN.read().await.contains_block(&hash) {
+        return;
+    }
+    if let Err(e) = crate::relay::accept_block(block, &source).await {
+        println!("block {} rejected after catching up: {}", hash, e);
+        // inbound peers are keyed by their socket address
+        let peer = match crate::PEERS.get(&source) {
+            Some(client) => Some(client.peer_addr()),
+            None => source.parse::<SocketAddr>().ok(),
+        };
+        if let Some(peer) = peer {
+            crate::BANS.invalid_block(peer, &e);
+        }
+    }
+}
+
+// download the headers of a node's chain, then the blocks we are missing
+// from every known node
+async fn sync_from(node: &str) -> Result<()> {
+    let headers = download_headers(node).await?;
+    let missing = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        if headers.work() <= blockchain.work() {
+            println!("{} has no chain with more work than ours", node);
+            return Ok(());
+        }
+        headers
+            .hashes()
+            .iter()
+            .filter(|hash| !blockchain.contains_block(hash))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+
+    println!("downloading {} blocks", missing.len());
+    download_blocks(missing).await
+}
+
+// follow a node's headers from where its chain forks from ours,
+// validating each of them
+async fn download_headers(node: &str) -> Result<HeaderChain> {
+    let mut headers = HeaderChain::new(&*crate::BLOCKCHAIN.read().await);
+    loop {
+        let client = crate::PEERS.get(node).context("no node")?;
+        let message = client
+            .request(Message::GetHeaders(headers.locator()))
+            .await?;
+        let Message::Headers(batch) = message else {
+            crate::BANS.protocol_violation(client.peer_addr(), "answered GetHeaders wrongly");
+            return Err(anyhow!("unexpected message from {}", node));
+        };
+
+        let count = batch.len();
+        if let Err(e) = headers.extend(batch) {
+            crate::BANS.invalid_block(client.peer_addr(), &e);
+            return Err(e).with_context(|| format!("{} sent invalid headers", node));
+        }
+        println!(
+            "received {} headers from {}, {} in total",
+            count,
+            node,
+            headers.block_height()
+        );
+        if count < MAX_HEADERS_PER_MESSAGE {
+            return Ok(headers);
+        }
+    }
+}
+
+// fetch the blocks MAX_BLOCKS_IN_FLIGHT at a time, spread over all known
+// nodes, and add each batch to the blockchain in chain order
+async fn download_blocks(hashes: Vec<Hash>) -> Result<()> {
+    let nodes = crate::PEERS.connected();
+
+    for batch in hashes.chunks(MAX_BLOCKS_IN_FLIGHT) {
+        let mut requests = JoinSet::new();
+        for (idx, hash) in batch.iter().copied().enumerate() {
+            let nodes = nodes.clone();
+            requests.spawn(async move { (idx, fetch_block(&nodes, idx, hash).await) });
+        }
+
+        let mut blocks: Vec<Option<(String, Block)>> = vec![None; batch.len()];
+        while let Some(result) = requests.join_next().await {
+            let (idx, block) = result?;
+            blocks[idx] = Some(block?);
+        }
+
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        for (node, block) in blocks.into_iter().flatten() {
+            // may have been broadcast to us in the meantime
+            if blockchain.contains_block(&block.hash()) {
+                continue;
+            }
+            let hash = block.hash();
+            let outcome = match blockchain.add_block(block) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    if let Some(client) = crate::PEERS.get(&node) {
+                        crate::BANS.invalid_block(client.peer_addr(), &e);
+                    }
+                    return Err(e).with_context(|| format!("{} sent invalid block {}", node, hash));
+                }
+            };
+            // connecting is what syncing does, only report the unusual
+            if outcome != BlockOutcome::Connected {
+                crate::util::log_block_outcome(&hash, outcome);
+            }
+        }
+    }
+    Ok(())
+}
+
+// ask the nodes in turn until one sends the block, starting
+// from a different node for every idx. returns the node that sent it
+async fn fetch_block(nodes: &[String], idx: usize, hash: Hash) -> Result<(String, Block)> {
+    for i in 0..nodes.len() {
+        let node = &nodes[(idx + i) % nodes.len()];
+        let Some(client) = crate::PEERS.get(node) else {
+            continue;
+        };
+        match client.request(Message::FetchBlock(hash)).await {
+            Ok(Message::NewBlock(block)) if block.hash() == hash => {
+                return Ok((node.clone(), block))
+            }
+            Ok(Message::NotFound(_)) => println!("{} does not have block {}", node, hash),
+            Ok(_) => {
+                println!("unexpected message from {}", node);
+                crate::BANS.protocol_violation(client.peer_addr(), "answered FetchBlock wrongly");
+            }
+            Err(e) => println!("failed to fetch block {} from {}: {}", hash, node, e),
+        }
+    }
+    Err(anyhow!("no node sent block {}", hash))
+}
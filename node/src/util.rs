@@ -1,20 +1,243 @@
-use anyhow::Result;
-use btc_lib::{types::Blockchain, util::Saveable};
+use anyhow::{anyhow, Result};
+use btc_lib::{
+    crypto::PublicKey,
+    network::{Client, Message, Version, SERVICE_FULL_NODE},
+    params::ChainParams,
+    sha256::Hash,
+    types::{BlockOutcome, Blockchain, MempoolSnapshot, TransactionOutput},
+    util::Saveable,
+};
+use static_init::dynamic;
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
+use crate::{bans::BanList, peers::AddressBook};
+use std::net::SocketAddr;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    time::{self, Duration},
+};
+
+// sent in our Version, a peer announcing the same one is this node
+#[dynamic]
+pub static LOCAL_NONCE: u64 = rand::random();
+
+// the chain params and the Version we announce to peers
+pub async fn local_version() -> (ChainParams, Version) {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let params = blockchain.params().clone();
+    let user_agent = format!("/rsbtc-node:{}/", env!("CARGO_PKG_VERSION"));
+    let mut version = Version::new(
+        &params,
+        blockchain.block_height(),
+        user_agent,
+        SERVICE_FULL_NODE,
+    );
+    version.nonce = *LOCAL_NONCE;
+    (params, version)
+}
+
+// connect to another node and complete the handshake
+pub async fn connect(address: &str) -> Result<Client> {
+    let (params, version) = local_version().await;
+    let (client, broadcasts) = Client::connect(address, &params, version).await?;
+    let peer = client.peer_version();
+    println!(
+        "connected to {} running {} at height {}",
+        address, peer.user_agent, peer.best_height
+    );
+    tokio::spawn(handle_broadcasts(
+        address.to_string(),
+        client.peer_addr(),
+        broadcasts,
+    ));
+    Ok(client)
+}
+
+// handle the gossip a node sends on our connection to it
+async fn handle_broadcasts(
+    address: String,
+    peer: SocketAddr,
+    mut broadcasts: mpsc::UnboundedReceiver<Message>,
+) {
+    while let Some(message) = broadcasts.recv().await {
+        crate::PEERS.seen(&address);
+        match message {
+            Message::Inv(items) => crate::relay::handle_inv(items, &address).await,
+            Message::GetData(items) => crate::relay::handle_get_data(items, &address).await,
+            Message::NewBlock(block) => {
+                println!("received new block from {}", address);
+                if let Err(e) = crate::relay::accept_relayed_block(block, &address).await {
+                    println!("block rejected: {e}");
+                    if crate::BANS.invalid_block(peer, &e) {
+                        break;
+                    }
+                }
+            }
+            Message::NewTransaction(transaction) => {
+                println!("received new transaction from {}", address);
+                if let Err(e) = crate::relay::accept_transaction(transaction, &address).await {
+                    println!("transaction rejected: {e}");
+                    if crate::BANS.invalid_transaction(peer, &e) {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                println!("unexpected broadcast from {}", address);
+                if crate::BANS.protocol_violation(peer, "sent a response message as a broadcast") {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// the unspent outputs locked to a key, with their hash and whether a
+// mempool transaction spends them. outputs of pending transactions
+// can be spent too, so they are included
+pub fn utxos_of(blockchain: &Blockchain, key: &PublicKey) -> Vec<(Hash, TransactionOutput, bool)> {
+    // without the index every utxo has to be checked
+    let confirmed: Vec<(Hash, &TransactionOutput)> = match blockchain.index() {
+        Some(index) => index
+            .unspent_of(key)
+            .map(|record| (record.hash, &record.output))
+            .collect(),
+        None => blockchain
+            .utxos()
+            .iter()
+            .map(|(hash, output)| (*hash, output))
+            .collect(),
+    };
+    confirmed
+        .into_iter()
+        .chain(blockchain.mempool().outputs())
+        .filter(|(_, output)| output.pubkey == *key)
+        .map(|(hash, output)| {
+            let spent = blockchain.mempool().spender(&hash).is_some();
+            (hash, output.clone(), spent)
+        })
+        .collect()
+}
+
+// report what adding a block did to the blockchain
+pub fn log_block_outcome(hash: &Hash, outcome: BlockOutcome) {
+    match outcome {
+        BlockOutcome::Connected => println!("block {} extends the chain", hash),
+        BlockOutcome::SideBranch => println!("stored block {} on a side branch", hash),
+        BlockOutcome::Reorganized { fork_height } => println!(
+            "block {} completes a side branch with more work, reorganized from height {}",
+            hash,
+            fork_height + 1
+        ),
+    }
+}
+
+pub async fn load_blockchain(blockchain_file: &str, params: &ChainParams) -> Result<()> {
     println!("blockchain file exists, loading...");
 
-    let new_blockchain = Blockchain::load_from_file(&blockchain_file)?;
+    let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
+    if new_blockchain.params() != params {
+        return Err(anyhow!(
+            "{} was created for {} with chain params that differ from the current {} params",
+            blockchain_file,
+            new_blockchain.params().network,
+            params.network
+        ));
+    }
     println!("blockchain loaded");
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     *blockchain = new_blockchain;
     println!("rebuilding utxos...");
-    blockchain.rebuild_utxos();
+    blockchain.rebuild_utxos()?;
     println!("utxos rebuilt");
-    println!("checking if target needs to be adjusted...");
-    println!("current target {}", blockchain.target());
-    blockchain.try_adjust_target();
-    println!("new target: {}", blockchain.target());
+    // blocks are checked against the target, replay the adjustments
+    // rather than trusting the saved one
+    println!("recalculating target...");
+    blockchain.recalculate_target();
+    println!("current target: {}", blockchain.target());
     println!("initialization complete");
     Ok(())
 }
+
+pub async fn save_blockchain(blockchain_file: &str) -> Result<()> {
+    println!("saving blockchain to {}...", blockchain_file);
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    blockchain.save_to_file(blockchain_file)?;
+    println!("blockchain saved");
+    Ok(())
+}
+
+// restore the transactions pending when the node last shut down
+pub async fn load_mempool(mempool_file: &str) -> Result<()> {
+    println!("mempool file exists, loading...");
+    let snapshot = MempoolSnapshot::load_from_file(mempool_file)?;
+    let saved = snapshot.transactions.len();
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    let restored = blockchain.restore_mempool(snapshot);
+    println!(
+        "restored {} of {} saved mempool transactions",
+        restored, saved
+    );
+    Ok(())
+}
+
+pub async fn save_mempool(mempool_file: &str) -> Result<()> {
+    println!("saving mempool to {}...", mempool_file);
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    blockchain.mempool().snapshot().save_to_file(mempool_file)?;
+    println!("mempool saved");
+    Ok(())
+}
+
+pub fn load_address_book(address_book_file: &str) -> Result<()> {
+    println!("address book exists, loading...");
+    let address_book = AddressBook::load_from_file(address_book_file)?;
+    crate::PEERS.load_address_book(address_book);
+    Ok(())
+}
+
+pub fn save_address_book(address_book_file: &str) -> Result<()> {
+    println!("saving address book to {}...", address_book_file);
+    crate::PEERS
+        .address_book()
+        .save_to_file(address_book_file)?;
+    println!("address book saved");
+    Ok(())
+}
+
+pub fn load_ban_list(ban_list_file: &str) -> Result<()> {
+    println!("ban list exists, loading...");
+    let ban_list = BanList::load_from_file(ban_list_file)?;
+    crate::BANS.load_ban_list(ban_list);
+    Ok(())
+}
+
+pub fn save_ban_list(ban_list_file: &str) -> Result<()> {
+    println!("saving ban list to {}...", ban_list_file);
+    crate::BANS.ban_list().save_to_file(ban_list_file)?;
+    println!("ban list saved");
+    Ok(())
+}
+
+// periodically save the blockchain so a crash loses at most one interval
+pub async fn save(blockchain_file: String, interval_secs: u64) {
+    let mut interval = time::interval(Duration::from_secs(interval_secs));
+    // the first tick completes immediately, skip it
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = save_blockchain(&blockchain_file).await {
+            println!("failed to save blockchain: {}", e);
+        }
+    }
+}
+
+// resolves once the process receives SIGINT or SIGTERM
+pub async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
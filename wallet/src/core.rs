@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+use btc_lib::{
+    crypto::{PrivateKey, PublicKey, Signature},
+    network::{Client, Message, Version},
+    params::ChainParams,
+    sha256::Hash,
+    types::{Amount, SigHashType, Transaction, TransactionInput, TransactionOutput},
+    util::Saveable,
+};
+use uuid::Uuid;
+
+pub struct Key {
+    pub public: PublicKey,
+    pub private: PrivateKey,
+}
+
+impl Key {
+    pub fn load_from_file(path: &str) -> Result<Self> {
+        let private = PrivateKey::load_from_file(path)
+            .map_err(|e| anyhow!("error reading private key file {}: {}", path, e))?;
+        Ok(Key {
+            public: private.public_key(),
+            private,
+        })
+    }
+}
+
+pub struct Core {
+    keys: Vec<Key>,
+    client: Client,
+    // utxos for every key, in the same order as keys. Bool determines if marked
+    utxos: Vec<Vec<(TransactionOutput, bool)>>,
+}
+
+impl Core {
+    pub async fn new(address: &str, params: &ChainParams, keys: Vec<Key>) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(anyhow!("at least one private key is required"));
+        }
+        let user_agent = format!("/rsbtc-wallet:{}/", env!("CARGO_PKG_VERSION"));
+        let version = Version::new(params, 0, user_agent, 0);
+        // the wallet has no use for broadcasts
+        let (client, _) = Client::connect(address, params, version).await?;
+        Ok(Core {
+            utxos: keys.iter().map(|_| vec![]).collect(),
+            keys,
+            client,
+        })
+    }
+
+    // ask the node for the utxos of every loaded key
+    pub async fn fetch_utxos(&mut self) -> Result<()> {
+        for (idx, key) in self.keys.iter().enumerate() {
+            let message = Message::FetchUTXOs(key.public.clone());
+            match self.client.request(message).await? {
+                Message::UTXOs(utxos) => self.utxos[idx] = utxos,
+                _ => return Err(anyhow!("unexpected message received when fetching utxos")),
+            }
+        }
+        Ok(())
+    }
+
+    // the fee rate the node's mempool asks for, in satoshis per 1000 bytes
+    pub async fn fetch_fee_rate(&self) -> Result<u64> {
+        match self.client.request(Message::FetchFeeRate).await? {
+            Message::FeeRate(rate) => Ok(rate),
+            _ => Err(anyhow!(
+                "unexpected message received when fetching the fee rate"
+            )),
+        }
+    }
+
+    // balance of a single key, excluding outputs already spent in the mempool
+    pub fn balance(&self, idx: usize) -> Result<Amount> {
+        Ok(Amount::checked_sum(
+            self.utxos[idx]
+                .iter()
+                .filter(|(_, marked)| !marked)
+                .map(|(output, _)| output.value),
+        )?)
+    }
+
+    pub fn total_balance(&self) -> Result<Amount> {
+        let balances = (0..self.keys.len())
+            .map(|idx| self.balance(idx))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Amount::checked_sum(balances)?)
+    }
+
+    // build and sign a transaction paying amount to recipient, with
+    // any change going back to the first key
+    pub fn create_transaction(
+        &self,
+        recipient: &PublicKey,
+        amount: Amount,
+        fee: Amount,
+    ) -> Result<Transaction> {
+        let total_amount = amount.checked_add(fee)?;
+
+        // select coins, remembering which key has to sign for each of them
+        let mut selected: Vec<(Hash, &Key)> = vec![];
+        let mut input_sum = Amount::ZERO;
+
+        'select: for (key, utxos) in self.keys.iter().zip(&self.utxos) {
+            for (output, marked) in utxos {
+                if input_sum >= total_amount {
+                    break 'select;
+                }
+                if *marked {
+                    continue;
+                }
+                selected.push((output.hash(), key));
+                input_sum = input_sum.checked_add(output.value)?;
+            }
+        }
+
+        if input_sum < total_amount {
+            return Err(anyhow!(
+                "insufficient funds: need {} but only {} is available",
+                total_amount,
+                input_sum
+            ));
+        }
+
+        let mut outputs = vec![TransactionOutput {
+            value: amount,
+            unique_id: Uuid::new_v4(),
+            pubkey: recipient.clone(),
+        }];
+
+        // pay the change back to ourselves
+        if input_sum > total_amount {
+            outputs.push(TransactionOutput {
+                value: input_sum.checked_sub(total_amount)?,
+                unique_id: Uuid::new_v4(),
+                pubkey: self.keys[0].public.clone(),
+            });
+        }
+
+        // every input signs all inputs and outputs of the transaction
+        let prev_output_hashes: Vec<Hash> = selected.iter().map(|(hash, _)| *hash).collect();
+        let mut inputs = vec![];
+        for (input_index, (output_hash, key)) in selected.iter().enumerate() {
+            let sighash = Transaction::signature_hash_for(
+                &prev_output_hashes,
+                &outputs,
+                input_index,
+                SigHashType::All,
+            )?;
+            inputs.push(TransactionInput {
+                prev_transaction_output_hash: *output_hash,
+                signature: Signature::sign_hash(&sighash, &key.private),
+                sighash_type: SigHashType::All,
+            });
+        }
+
+        Ok(Transaction::new(inputs, outputs))
+    }
+
+    // like create_transaction, with the smallest fee paying fee_rate
+    // satoshis per 1000 bytes. the fee changes the size, so the
+    // transaction is built again until the fee covers it
+    pub fn create_transaction_at_rate(
+        &self,
+        recipient: &PublicKey,
+        amount: Amount,
+        fee_rate: u64,
+    ) -> Result<Transaction> {
+        let mut fee = Amount::ZERO;
+        loop {
+            let transaction = self.create_transaction(recipient, amount, fee)?;
+            let size = transaction.size() as u64;
+            let required = Amount::from_sat(size.saturating_mul(fee_rate).div_ceil(1000))?;
+            if fee >= required {
+                return Ok(transaction);
+            }
+            fee = required;
+        }
+    }
+
+    // fails with the node's reason if it rejects the transaction
+    pub async fn submit_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        let message = Message::SubmitTransaction(transaction);
+        match self.client.request(message).await? {
+            Message::TransactionStatus(Ok(())) => Ok(()),
+            Message::TransactionStatus(Err(reason)) => {
+                Err(anyhow!("the node rejected the transaction: {}", reason))
+            }
+            _ => Err(anyhow!(
+                "unexpected message received when submitting a transaction"
+            )),
+        }
+    }
+}
@@ -1,3 +1,96 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::{anyhow, Result};
+use btc_lib::{
+    crypto::PublicKey,
+    params::{ChainParams, Network},
+    types::Amount,
+    util::Saveable,
+};
+use clap::{Parser, Subcommand};
+
+mod core;
+
+use crate::core::{Core, Key};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Cli {
+    /// network to use: mainnet, testnet or regtest
+    #[arg(short, long, default_value_t = Network::Mainnet)]
+    network: Network,
+    /// node address, defaults to the network's port on localhost
+    #[arg(short, long)]
+    address: Option<String>,
+    /// private key files, can be repeated
+    #[arg(short, long = "key-file", required = true)]
+    key_files: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// show the balance of every loaded key
+    Balance,
+    /// send satoshis to the owner of a public key
+    Send {
+        /// recipient public key file
+        #[arg(short, long)]
+        recipient: String,
+        /// amount in satoshis
+        #[arg(short = 'm', long)]
+        amount: u64,
+        /// fee in satoshis, estimated from the transaction size and the
+        /// node's current fee rate if not given
+        #[arg(short, long)]
+        fee: Option<u64>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let keys = cli
+        .key_files
+        .iter()
+        .map(|path| Key::load_from_file(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let params = ChainParams::for_network(cli.network);
+    let address = cli
+        .address
+        .unwrap_or_else(|| format!("127.0.0.1:{}", params.default_port));
+
+    let mut core = Core::new(&address, &params, keys).await?;
+    core.fetch_utxos().await?;
+
+    match cli.command {
+        Command::Balance => {
+            for (idx, path) in cli.key_files.iter().enumerate() {
+                println!("{}: {} satoshis", path, core.balance(idx)?);
+            }
+            println!("total: {} satoshis", core.total_balance()?);
+        }
+        Command::Send {
+            recipient,
+            amount,
+            fee,
+        } => {
+            let recipient = PublicKey::load_from_file(&recipient)
+                .map_err(|e| anyhow!("error reading public key file: {}", e))?;
+            let amount = Amount::from_sat(amount)?;
+            let transaction = match fee {
+                Some(fee) => core.create_transaction(&recipient, amount, Amount::from_sat(fee)?)?,
+                None => {
+                    let fee_rate = core.fetch_fee_rate().await?;
+                    println!("node asks for {} satoshis per 1000 bytes", fee_rate);
+                    core.create_transaction_at_rate(&recipient, amount, fee_rate)?
+                }
+            };
+            println!("submitting transaction {}", transaction.hash());
+            core.submit_transaction(transaction).await?;
+            println!("transaction accepted by the node");
+        }
+    }
+    Ok(())
 }